@@ -1,63 +1,94 @@
-use crate::{symbol_table::SymbolTable, types};
+use crate::{error::RuntimeError, symbol_table::Env, types};
 use text_io::read;
 
 macro_rules! builtin_func {
     ($name:ident($env:ident, $params:ident) $func:block) => {
-        pub fn $name($env: &mut SymbolTable, $params: Vec<Box<types::Type>>) -> types::Type $func
+        pub fn $name($env: &Env, $params: Vec<Box<types::Type>>) -> Result<types::Type, RuntimeError> $func
     };
 }
 
 builtin_func! {
     print(env, _params) {
-        if let Some(types::Type::List(params)) = env.symbols.get("ARGV") {
+        if let Some(types::Type::List(params)) = env.clone_item("ARGV") {
             for param in params {
-                let data = param.into_string();
+                let data = param.into_string()?;
                 print!("{}", data);
             }
         }
-        types::Type::Int(0)
+        Ok(types::Type::Nil)
     }
 }
 
 builtin_func! {
     println(env, _params){
-        if let Some(types::Type::List(params)) = env.symbols.get("ARGV"){
+        if let Some(types::Type::List(params)) = env.clone_item("ARGV"){
             for param in params {
-                let data = param.into_string();
+                let data = param.into_string()?;
                 print!("{}", data);
             }
         }
         println!();
-        types::Type::Int(0)
+        Ok(types::Type::Nil)
     }
 }
 
 builtin_func! {
     set(env, _params){
-        if let Some(types::Type::StructInstance { name, mut fields }) = env.symbols.remove("str") {
-            if let Some(types::Type::String(x)) = env.symbols.get("name") {
-                if let Some(t) = env.symbols.get("val") {
-                    fields.insert(x.to_owned(), t.to_owned());
-                    return types::Type::StructInstance {
+        if let Some(types::Type::StructInstance { name, mut fields, methods }) = env.clone_item("str") {
+            if let Some(types::Type::String(x)) = env.clone_item("name") {
+                if let Some(t) = env.clone_item("val") {
+                    fields.insert(x, t);
+                    return Ok(types::Type::StructInstance {
                         name: name.to_owned(),
                         fields: fields.to_owned(),
-                    };
+                        methods,
+                    });
                 }
             }
         }
-        panic!("Cannot use set on non-struct")
+        Err(RuntimeError::new("Cannot use set on non-struct".to_owned()))
     }
 }
 
 builtin_func!{
     repr(_env, params) {
-        types::Type::String(params[0].into_repr())
+        match params.first() {
+            Some(param) => Ok(types::Type::String(param.into_repr()?)),
+            None => Err(RuntimeError::new("repr expects 1 argument".to_owned())),
+        }
+    }
+}
+
+builtin_func!{
+    keys(_env, params) {
+        match params.first().map(|p| p.as_ref()) {
+            Some(types::Type::Map(entries)) => Ok(types::Type::List(entries.values().map(|(k, _)| Box::new(k.clone())).collect())),
+            _ => Err(RuntimeError::new("keys expects a map".to_owned())),
+        }
+    }
+}
+
+builtin_func!{
+    values(_env, params) {
+        match params.first().map(|p| p.as_ref()) {
+            Some(types::Type::Map(entries)) => Ok(types::Type::List(entries.values().map(|(_, v)| Box::new(v.clone())).collect())),
+            _ => Err(RuntimeError::new("values expects a map".to_owned())),
+        }
+    }
+}
+
+builtin_func!{
+    has(_env, params) {
+        match (params.first().map(|p| p.as_ref()), params.get(1)) {
+            (Some(types::Type::Map(entries)), Some(key)) => Ok(types::Type::Bool(entries.contains_key(&key.map_key()?))),
+            _ => Err(RuntimeError::new("has expects a map and a key".to_owned())),
+        }
     }
 }
 
 builtin_func!{
     input(_env, _params) {
         let line = read!("{}\n");
-        types::Type::String(line)
+        Ok(types::Type::String(line))
     }
 }
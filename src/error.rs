@@ -0,0 +1,203 @@
+use crate::span::Span;
+use crate::tokens::TT;
+
+/// A malformed piece of source text, such as an unterminated comment.
+#[derive(Clone, Debug)]
+pub struct LexError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl LexError {
+    pub fn new(message: String, span: Span) -> LexError {
+        return LexError { message, span };
+    }
+
+    pub fn render(&self, source: &str) -> String {
+        return render_snippet(source, &self.span, &self.message);
+    }
+}
+
+/// A grammar error: what the parser expected, the token it found instead and where.
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    pub expected: String,
+    pub found: Option<TT>,
+    pub span: Span,
+    pub hint: Option<String>,
+    /// Replaces the usual "expected ..., found ..." when that would not describe the problem.
+    pub message: Option<String>,
+}
+
+impl ParseError {
+    pub fn new(expected: &str, found: Option<TT>, span: Span) -> ParseError {
+        return ParseError {
+            expected: expected.to_owned(),
+            found,
+            span,
+            hint: None,
+            message: None,
+        };
+    }
+
+    /// An opening `delimiter` at `span` that nothing closes, so it is reported where it starts.
+    pub fn unclosed(delimiter: &str, closer: &str, span: Span) -> ParseError {
+        let mut err = ParseError::new(&format!("'{}'", closer), None, span);
+        err.message = Some(format!("unclosed '{}'", delimiter));
+        return err.with_hint(&format!("no '{}' closes it", closer));
+    }
+
+    pub fn with_hint(mut self, hint: &str) -> ParseError {
+        self.hint = Some(hint.to_owned());
+        return self;
+    }
+
+    /// The error message, quoting the found token as it was written in `source`.
+    pub fn message(&self, source: &str) -> String {
+        if let Some(message) = &self.message {
+            return message.clone();
+        }
+        let found = match (&self.found, source.get(self.span.start..self.span.end)) {
+            (Some(_), Some(text)) => format!("'{}'", text),
+            (Some(tok), None) => format!("'{}'", tok.to_string()),
+            (None, _) => "end of input".to_owned(),
+        };
+        return format!("expected {}, found {}", self.expected, found);
+    }
+
+    /// Renders the error with the offending source line underlined.
+    pub fn render(&self, source: &str) -> String {
+        let mut span = self.span;
+        // there were no tokens to point at, so point at the end of the file instead
+        if span.line == 0 {
+            span = end_of_input(source);
+        }
+        let mut text = render_snippet(source, &span, &self.message(source));
+        if let Some(hint) = &self.hint {
+            text += &format!("\n  = hint: {}", hint);
+        }
+        return text;
+    }
+}
+
+/// An empty span just past the last non-whitespace character of `source`.
+fn end_of_input(source: &str) -> Span {
+    let source = source.trim_end();
+    let line_start = match source.rfind('\n') {
+        Some(idx) => idx + 1,
+        None => 0,
+    };
+    let line = source.matches('\n').count() + 1;
+    let col = source[line_start..].chars().count() + 1;
+    return Span::new(source.len(), source.len(), line, col);
+}
+
+/// Formats `message` followed by the line `span` starts on, with the spanned part underlined by carets.
+pub fn render_snippet(source: &str, span: &Span, message: &str) -> String {
+    let mut text = format!("error: {}\n --> {}:{}", message, span.line, span.col);
+    if span.line == 0 {
+        return text;
+    }
+    let start = std::cmp::min(span.start, source.len());
+    let line_start = match source[..start].rfind('\n') {
+        Some(idx) => idx + 1,
+        None => 0,
+    };
+    let line_end = match source[line_start..].find('\n') {
+        Some(idx) => line_start + idx,
+        None => source.len(),
+    };
+    let line = source[line_start..line_end].trim_end_matches('\r');
+    let end = std::cmp::min(std::cmp::max(span.end, start), line_end);
+    let gutter = span.line.to_string();
+    let pad = " ".repeat(gutter.len());
+    // columns are counted in chars so multi-byte text lines up with the carets
+    let underline_start = source[line_start..start].chars().count();
+    let underline_len = std::cmp::max(source[start..end].chars().count(), 1);
+    text += &format!("\n{} |\n{} | {}\n{} | ", pad, gutter, line, pad);
+    text += &" ".repeat(underline_start);
+    text += &"^".repeat(underline_len);
+    return text;
+}
+
+/// An escr function call that a runtime error unwound through.
+#[derive(Clone, Debug)]
+pub struct TraceFrame {
+    pub name: String,
+    pub call_site: Span,
+}
+
+/// How many times in a row the same frame is shown in a traceback before the rest are counted.
+const MAX_REPEATED_FRAMES: usize = 3;
+
+/// The line summarising the frames left out after `repeats` repeats of the same one.
+fn repeated_frames(repeats: usize) -> String {
+    if repeats < MAX_REPEATED_FRAMES {
+        return String::new();
+    }
+    return format!("  [previous frame repeated {} more times]\n", repeats - MAX_REPEATED_FRAMES + 1);
+}
+
+/// An error raised while running a program.
+///
+/// `trace` is ordered innermost call first, as frames are added while the error propagates outwards.
+#[derive(Clone, Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    pub span: Option<Span>,
+    pub trace: Vec<TraceFrame>,
+}
+
+impl RuntimeError {
+    pub fn new(message: String) -> RuntimeError {
+        return RuntimeError {
+            message,
+            span: None,
+            trace: vec![],
+        };
+    }
+
+    /// Records where the error happened, unless a more precise location is already known.
+    pub fn at(mut self, span: Span) -> RuntimeError {
+        if self.span.is_none() {
+            self.span = Some(span);
+        }
+        return self;
+    }
+
+    pub fn with_frame(mut self, name: String, call_site: Span) -> RuntimeError {
+        self.trace.push(TraceFrame { name, call_site });
+        return self;
+    }
+
+    /// Renders the escr call stack, most recent call last, followed by the error itself.
+    pub fn render(&self, source: &str) -> String {
+        let mut text = String::new();
+        if !self.trace.is_empty() {
+            text += "Traceback (most recent call last):\n";
+            // deep recursion repeats the same frame many times, so only the first few are shown
+            let mut repeats = 0;
+            for (i, frame) in self.trace.iter().rev().enumerate() {
+                let previous = self.trace.len().checked_sub(i).and_then(|idx| self.trace.get(idx));
+                if previous.map_or(false, |prev| prev.name == frame.name && prev.call_site == frame.call_site) {
+                    repeats += 1;
+                    if repeats >= MAX_REPEATED_FRAMES {
+                        continue;
+                    }
+                } else {
+                    text += &repeated_frames(repeats);
+                    repeats = 0;
+                }
+                let line = source.lines().nth(frame.call_site.line.saturating_sub(1)).unwrap_or("");
+                text += &format!("  {}:{}, in call to {}\n", frame.call_site.line, frame.call_site.col, frame.name);
+                text += &format!("    {}\n", line.trim());
+            }
+            text += &repeated_frames(repeats);
+        }
+        match &self.span {
+            Some(span) => text += &render_snippet(source, span, &self.message),
+            None => text += &format!("error: {}", self.message),
+        }
+        return text;
+    }
+}
@@ -1,14 +1,14 @@
-use crate::symbol_table::SymbolTable;
-use crate::util::{String_vec,str};
+use crate::error::RuntimeError;
+use crate::symbol_table::Env;
 use crate::{builtin_functions, nodes, types};
 // use crate::tokens::TT;
 use crate::types::Type;
 
 macro_rules! insert_func {
-    ($name:ident ($($p:literal$(,)?)*) { $exec:expr } into $symbols:ident) => {
+    ($name:ident ($($p:literal$(,)?)*) { $exec:expr } into $env:ident) => {
         let name = stringify!($name);
-        $symbols.symbols.insert(
-            name.to_string(),
+        $env.define(
+            name,
             types::Type::BuiltinFunction {
                 name: name.to_string(),
                 parameters: vec![$( $p.to_owned(), )*],
@@ -18,31 +18,257 @@ macro_rules! insert_func {
     };
 }
 
-pub fn interpret<'a>(program: nodes::Node, symbols: &mut SymbolTable) -> Type {
+pub fn interpret(program: nodes::Node, env: &Env) -> Result<Type, RuntimeError> {
     insert_func!(
         print("...text"){
             builtin_functions::print
-        } into symbols
+        } into env
     );
     insert_func!(
         input(){
             builtin_functions::input
-        } into symbols
+        } into env
     );
     insert_func!(
         println("...text"){
             builtin_functions::println
-        } into symbols
+        } into env
     );
     insert_func!(
         repr("text"){
             builtin_functions::repr
-        } into symbols
+        } into env
+    );
+    insert_func!(
+        keys("map"){
+            builtin_functions::keys
+        } into env
+    );
+    insert_func!(
+        values("map"){
+            builtin_functions::values
+        } into env
+    );
+    insert_func!(
+        has("map", "key"){
+            builtin_functions::has
+        } into env
     );
     insert_func!(
         set("str", "name", "val"){
             builtin_functions::set
-        } into symbols
+        } into env
     );
-    program.visit(symbols)
+    program.visit(env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser};
+
+    fn run(text: &str) -> Result<Type, RuntimeError> {
+        let toks = Lexer::new(text.to_owned()).parse().expect("source should lex");
+        let program = Parser::new(toks).parse().expect("source should parse");
+        return interpret(program, &Env::new(None));
+    }
+
+    fn int(text: &str) -> i64 {
+        match run(text) {
+            Ok(Type::Int(n)) => n,
+            other => panic!("expected an Int, got {:?}", other),
+        }
+    }
+
+    fn repr(text: &str) -> String {
+        return run(text).and_then(|value| value.into_repr()).expect("source should run");
+    }
+
+    fn error(text: &str) -> String {
+        return run(text).expect_err("source should fail").message;
+    }
+
+    #[test]
+    fn closures_capture_their_defining_scope() {
+        let text = "
+            var make_adder(n) = var add(m) = n + m rav rav;
+            var add5 = make_adder(5);
+            var add1 = make_adder(1);
+            add5(3) * 10 + add1(1)";
+        assert_eq!(int(text), 82);
+    }
+
+    #[test]
+    fn names_resolve_where_the_function_was_written() {
+        let text = "
+            var x = 1;
+            var get() = x rav;
+            var shadow() = var x = 2; get() rav;
+            shadow()";
+        assert_eq!(int(text), 1);
+    }
+
+    #[test]
+    fn closures_keep_their_own_state() {
+        let text = "
+            var counter() = var n = 0; var inc() = n = n + 1 rav rav;
+            var a = counter();
+            var b = counter();
+            a(); a(); b();
+            a() * 10 + b()";
+        assert_eq!(int(text), 32);
+    }
+
+    #[test]
+    fn scopes_of_finished_calls_are_freed() {
+        let text = "var f() = var g() = 1 rav; var h = code g() edoc; h rav; f()";
+        let scope = match run(text) {
+            Ok(Type::Node { closure, .. }) => closure.downgrade(),
+            other => panic!("expected a code value, got {:?}", other),
+        };
+        assert!(!scope.is_alive());
+        let text = "var make() = var g() = 7 rav; var h = 0; g rav; var g = make(); g()";
+        assert_eq!(int(text), 7);
+    }
+
+    #[test]
+    fn assignment_updates_the_outer_variable() {
+        let text = "
+            var total = 0;
+            var add(n) = total = total + n rav;
+            add(2); add(3);
+            var i = 0;
+            while i < 3 do i = i + 1 end;
+            total * 10 + i";
+        assert_eq!(int(text), 53);
+    }
+
+    #[test]
+    fn var_shadows_instead_of_assigning() {
+        let text = "
+            var x = 1;
+            var f() = var x = 5; x = x + 1; x rav;
+            f() * 10 + x";
+        assert_eq!(int(text), 61);
+    }
+
+    #[test]
+    fn truthiness() {
+        let text = "
+            var f() = 1 rav;
+            struct S = a end;
+            var truthy = [f, println, code 1 edoc, S, S(0), \"a\", [0], {0: 0}, -1, 0.5];
+            var falsy = [false, nil, 0, 0.0, \"\", [], {}];
+            var n = 0;
+            var i = 0;
+            while i < truthy.length do if truthy[i] then n = n + 1 fi; i = i + 1 end;
+            i = 0;
+            while i < falsy.length do if not falsy[i] then n = n + 100 fi; i = i + 1 end;
+            n";
+        assert_eq!(int(text), 710);
+    }
+
+    #[test]
+    fn method_receiver_is_evaluated_once() {
+        let text = "
+            var k = 0;
+            var next() = k = k + 1; 0 rav;
+            struct C = n; var inc() = self.n = self.n + 1 rav; end;
+            var cs = [C(0)];
+            cs[next()].inc();
+            k * 10 + cs[0].n";
+        assert_eq!(int(text), 11);
+    }
+
+    #[test]
+    fn method_assignment_to_its_receiver_is_kept() {
+        let text = "
+            struct C = n; var set_other() = other = 7 rav; end;
+            var other = C(1);
+            other.set_other();
+            other";
+        assert_eq!(int(text), 7);
+        let text = "
+            struct C = n; var f() = self.n = 5; other = C(9) rav; end;
+            var other = C(1);
+            other.f();
+            other.n";
+        assert_eq!(int(text), 9);
+        let text = "
+            struct C = n; var f() = self.n = 5 rav; end;
+            var cs = [C(1)];
+            cs[0].f();
+            cs[0].n";
+        assert_eq!(int(text), 5);
+    }
+
+    #[test]
+    fn indexing_and_slicing() {
+        assert_eq!(repr("[[10, 20, 30][-1], \"escr\"[1], \"escr\"[-4]]"), "[30, \"s\", \"e\"]");
+        assert_eq!(repr("[[1, 2, 3, 4][1:3], [1, 2, 3][-2:], [1, 2, 3][:10], [1, 2, 3][2:1]]"), "[[2, 3], [2, 3], [1, 2, 3], []]");
+        assert_eq!(repr("[\"hello\"[-3:], \"hello\"[-10:2]]"), "[\"llo\", \"he\"]");
+        assert_eq!(error("[1, 2][2]"), "index 2 out of range for length 2");
+    }
+
+    #[test]
+    fn element_assignment() {
+        assert_eq!(repr("var xs = [1, [2, 3]]; xs[0] = 10; xs[-1][0] = 20; xs"), "[10, [20, 3]]");
+        assert_eq!(repr("var s = \"cat\"; s[-3] = \"b\"; s"), "\"bat\"");
+        assert!(error("var s = \"cat\"; s[0] = \"ab\"").starts_with("Can only put a single character"));
+    }
+
+    #[test]
+    fn maps() {
+        let text = "
+            var m = {\"a\": 1};
+            m[\"b\"] = 2;
+            m[\"a\"] = 3;
+            [keys(m), values(m), has(m, \"b\"), has(m, \"c\"), m.length, m]";
+        assert_eq!(repr(text), "[[\"a\", \"b\"], [3, 2], true, false, 2, {\"a\": 3, \"b\": 2}]");
+        assert_eq!(int("var m = {1: 5}; m[1.0] = 6; m[1] + m.length"), 7);
+        assert_eq!(error("var m = {\"a\": 1}; m[\"b\"]"), "key \"b\" not found");
+        assert_eq!(error("var f() = 1 rav; {f: 1}"), "Function cannot be used as a map key");
+    }
+
+    #[test]
+    fn nested_field_assignment() {
+        let text = "
+            struct Q = name, tags end;
+            var qs = {\"a\": [Q(\"x\", [1])]};
+            qs[\"a\"][0].tags[0] = 5;
+            qs[\"a\"][0].tags[0]";
+        assert_eq!(int(text), 5);
+        assert_eq!(error("struct P = name end; var p = P(1); p.age = 2"), "P has no field age");
+    }
+
+    #[test]
+    fn operator_methods() {
+        let text = "
+            struct V = x;
+                var add(o) = V(self.x + o.x) rav;
+                var lt(o) = self.x < o.x rav;
+                var eq(o) = self.x == o.x rav;
+            end;
+            [(V(1) + V(2)).x, V(1) < V(2), V(1) <= V(1), V(2) > V(1), V(2) >= V(3), V(1) != V(1), V(1) == V(1)]";
+        assert_eq!(repr(text), "[3, true, true, true, false, false, true]");
+        assert_eq!(error("struct V = x; var add(o) = self rav; end; 2 + V(1)"), "Cannot use + on Int and V");
+    }
+
+    #[test]
+    fn named_arguments_and_defaults() {
+        let decl = "struct P = name, score = 0 end;";
+        let text = format!("{} [P(name: \"a\").score, P(\"b\", score: 5).score, P(score: 2, name: \"c\").name]", decl);
+        assert_eq!(repr(&text), "[0, 5, \"c\"]");
+        assert_eq!(error(&format!("{} P()", decl)), "missing field(s) name for P");
+        assert_eq!(error(&format!("{} P(\"a\", name: \"b\")", decl)), "field name of P was given more than once");
+        assert_eq!(error(&format!("{} P(age: 1, name: \"a\")", decl)), "P has no field age");
+        assert_eq!(error(&format!("{} P(1, 2, 3)", decl)), "P has 2 field(s) but 3 were given");
+    }
+
+    #[test]
+    fn assigning_an_undeclared_variable_is_an_error() {
+        let err = run("var f() = y = 1 rav; f()").unwrap_err();
+        assert!(err.message.starts_with("y is not defined"), "{}", err.message);
+        assert_eq!(err.trace.len(), 1);
+    }
 }
@@ -1,4 +1,4 @@
-use crate::{tokens::{self, TT, Keyword}, util::String_vec, util::str};
+use crate::{error::LexError, span::Span, tokens::{self, TT, Token, Trivia, Keyword}};
 
 macro_rules! adv_or_break {
     ($self:ident) => {
@@ -8,50 +8,120 @@ macro_rules! adv_or_break {
     };
 }
 
+/// Splits source text into tokens.
+///
+/// The text is walked one `char` at a time, so `cur_idx` is a char index; spans are converted
+/// back to byte offsets through `offsets`.
 #[derive(Clone)]
 pub struct Lexer {
-    text: String,
-    tokens: Vec<tokens::TT>,
+    chars: Vec<char>,
+    offsets: Vec<usize>,
+    tokens: Vec<tokens::Token>,
+    trivia: Vec<Trivia>,
     cur_idx: usize,
+    line_starts: Vec<usize>,
 }
 
 impl Lexer {
     pub fn new(text: String) -> Lexer {
+        let mut chars = vec![];
+        let mut offsets = vec![];
+        let mut line_starts = vec![0];
+        for (offset, ch) in text.char_indices() {
+            chars.push(ch);
+            offsets.push(offset);
+            if ch == '\n' {
+                line_starts.push(chars.len());
+            }
+        }
+        offsets.push(text.len());
         return Lexer {
-            text,
+            chars,
+            offsets,
             tokens: vec![],
+            trivia: vec![],
             cur_idx: 0,
+            line_starts,
         };
     }
 
-    pub fn get_tokens(&self) -> Vec<tokens::TT> {
+    pub fn get_tokens(&self) -> Vec<tokens::Token> {
         return self.tokens.clone();
     }
 
-    pub fn parse(&mut self) -> Vec<tokens::TT> {
+    /// Comments skipped while lexing, in source order. The interpreter itself never reads them.
+    #[allow(dead_code)]
+    pub fn get_trivia(&self) -> Vec<Trivia> {
+        return self.trivia.clone();
+    }
+
+    /// Span of the source between the char indices `start` and `end`.
+    pub fn span(&self, start: usize, end: usize) -> Span {
+        let end = std::cmp::min(end, self.chars.len());
+        let line = match self.line_starts.binary_search(&start) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+        return Span::new(self.offsets[start], self.offsets[end], line + 1, start - self.line_starts[line] + 1);
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<tokens::Token>, LexError> {
+        if self.chars.is_empty() {
+            return Ok(self.get_tokens());
+        }
         loop {
+            let start = self.cur_idx;
             let cur_char = self.get_cur_char();
             let tok = match cur_char {
                 '+' => TT::PLUS,
                 '-' => TT::MINUS,
+                '*' if self.peek_char() == Some('*') => {
+                    self.advance();
+                    TT::POW
+                }
                 '*' => TT::MUL,
+                '%' => TT::MOD,
+                '~' if self.peek_char() == Some('/') => {
+                    self.advance();
+                    TT::IDIV
+                }
+                '/' if self.peek_char() == Some('/') => {
+                    self.skip_line_comment(start);
+                    adv_or_break!(self);
+                    continue;
+                }
+                '/' if self.peek_char() == Some('*') => {
+                    self.skip_block_comment(start)?;
+                    adv_or_break!(self);
+                    continue;
+                }
+                '#' => {
+                    self.skip_line_comment(start);
+                    adv_or_break!(self);
+                    continue;
+                }
                 '/' => TT::DIV,
                 '=' | '>' | '<' => self.build_comp(cur_char),
+                '!' if self.peek_char() == Some('=') => self.build_comp(cur_char),
                 '(' => TT::LPAREN,
                 ')' => TT::RPAREN,
                 ';' => TT::SEMI,
+                ':' => TT::COLON,
                 ',' => TT::COMMA,
                 '|' => TT::PIPE,
                 '[' => TT::LBRACKET,
                 ']' => TT::RBRACKET,
+                '{' => TT::LBRACE,
+                '}' => TT::RBRACE,
+                '.' if self.peek_char().map_or(false, |c| c.is_ascii_digit()) => self.build_number(cur_char)?,
                 '.' => TT::DOT,
-                '0'..='9' => self.build_number(cur_char),
-                ' ' | '\t' | '\n' => {
+                '0'..='9' => self.build_number(cur_char)?,
+                ' ' | '\t' | '\n' | '\r' => {
                     adv_or_break!(self);
                     continue;
                 }
-                '"' => self.build_string(),
-                'A'..='Z' | '_' | 'a'..='z' => {
+                '"' => self.build_string(start)?,
+                c if is_ident_start(c) => {
                     let str = self.build_ident_string(cur_char);
                     if let Some(kwd) = Keyword::is_keyword(&str){
                         TT::KEYWORD(kwd)
@@ -61,46 +131,71 @@ impl Lexer {
                     }
                 }
                 _ => {
-                    panic!("[Lexer] {} Invalid char", cur_char);
+                    return Err(LexError::new(format!("invalid character '{}'", cur_char), self.span(start, start + 1)));
                 }
             };
-            self.tokens.push(tok);
+            let span = self.span(start, self.cur_idx + 1);
+            self.tokens.push(Token { tt: tok, span });
             adv_or_break!(self);
         }
-        return self.get_tokens();
+        return Ok(self.get_tokens());
     }
 
-    fn build_comp(&mut self, cur_char: char) -> TT {
-        let mut comp = cur_char.to_string();
-
-        if self.advance() {
-            let ch = self.get_cur_char();
-            if ch != '=' {
-                self.back();
-                match comp.as_str() {
-                    "=" => crate::tokens::TT::EQ,
-                    ">" => crate::tokens::TT::GT,
-                    "<" => crate::tokens::TT::LT,
-                    _ => todo!("{} as comp token", comp)
-                }
-            } else {
-                comp += &ch.to_string();
-                match comp.as_str() {
-                    "==" => TT::EQEQ,
-                    ">=" => TT::GE,
-                    "<=" => TT::LE,
-                    _ => todo!("{} as comp token", comp)
-                }
+    /// Skips a `#` or `//` comment, stopping on its last character before the newline.
+    fn skip_line_comment(&mut self, start: usize) {
+        while self.advance() {
+            if self.get_cur_char() == '\n' {
+                break;
             }
-        } else {
-            self.back();
-            match comp.as_str() {
-                "=" => crate::tokens::TT::EQ,
-                ">" => crate::tokens::TT::GT,
-                "<" => crate::tokens::TT::LT,
-                _ => todo!("{} as comp token", comp)
+        }
+        self.back();
+        self.push_trivia(start);
+    }
+
+    /// Skips a `/* ... */` comment, which may contain nested block comments.
+    fn skip_block_comment(&mut self, start: usize) -> Result<(), LexError> {
+        self.advance();
+        let mut depth = 1;
+        while self.advance() {
+            let cur_char = self.get_cur_char();
+            if cur_char == '/' && self.peek_char() == Some('*') {
+                self.advance();
+                depth += 1;
+            } else if cur_char == '*' && self.peek_char() == Some('/') {
+                self.advance();
+                depth -= 1;
+                if depth == 0 {
+                    self.push_trivia(start);
+                    return Ok(());
+                }
             }
         }
+        return Err(LexError::new("unterminated block comment".to_owned(), self.span(start, start + 2)));
+    }
+
+    fn push_trivia(&mut self, start: usize) {
+        let end = self.cur_idx + 1;
+        self.trivia.push(Trivia {
+            text: self.chars[start..end].iter().collect(),
+            span: self.span(start, end),
+        });
+    }
+
+    fn build_comp(&mut self, cur_char: char) -> TT {
+        if self.peek_char() == Some('=') {
+            self.advance();
+            return match cur_char {
+                '>' => TT::GE,
+                '<' => TT::LE,
+                '!' => TT::NE,
+                _ => TT::EQEQ,
+            };
+        }
+        match cur_char {
+            '>' => TT::GT,
+            '<' => TT::LT,
+            _ => TT::EQ,
+        }
     }
 
     fn build_ident_string(&mut self, mut cur_char: char) -> String {
@@ -108,10 +203,7 @@ impl Lexer {
 
         while self.advance() {
             cur_char = self.get_cur_char();
-            if !match cur_char {
-                'A'..='Z' | '_' | 'a'..='z' => true,
-                _ => false,
-            } {
+            if !is_ident_continue(cur_char) {
                 break;
             }
 
@@ -121,62 +213,236 @@ impl Lexer {
         return ident;
     }
 
-    fn build_string(&mut self) -> TT {
+    fn build_string(&mut self, start: usize) -> Result<TT, LexError> {
         let mut string = String::new();
         let mut cur_char;
-        let mut escape = false;
         while self.advance() {
             cur_char = self.get_cur_char();
+            if cur_char == '"' {
+                return Ok(TT::STRING(string));
+            }
             if cur_char == '\\' {
-                escape = true;
-                continue;
+                cur_char = self.build_escape()?;
             }
-            if escape {
-                escape = false;
-                match cur_char {
-                    'n' => cur_char = '\n',
-                    't' => cur_char = '\t',
-                    _ => {}
+            string.push(cur_char);
+        }
+        return Err(LexError::new("unterminated string literal".to_owned(), self.span(start, start + 1)));
+    }
+
+    /// Reads the escape sequence after a `\` and returns the char it stands for.
+    fn build_escape(&mut self) -> Result<char, LexError> {
+        let start = self.cur_idx;
+        if !self.advance() {
+            return Err(LexError::new("unterminated string literal".to_owned(), self.span(start, start + 1)));
+        }
+        let escaped = match self.get_cur_char() {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            '\'' => '\'',
+            'u' => {
+                let mut code = String::new();
+                if self.peek_char() == Some('{') {
+                    self.advance();
+                    while self.advance() && self.get_cur_char() != '}' {
+                        code.push(self.get_cur_char());
+                    }
+                }
+                let ch = match code.len() {
+                    1..=6 => u32::from_str_radix(&code, 16).ok().and_then(char::from_u32),
+                    _ => None,
+                };
+                match ch {
+                    Some(ch) => ch,
+                    None => return Err(LexError::new(
+                        "invalid unicode escape, expected '\\u{...}' with 1 to 6 hex digits".to_owned(),
+                        self.span(start, self.cur_idx + 1),
+                    )),
                 }
-            } else if cur_char == '"' {
-                break;
             }
-            string += cur_char.to_string().as_str();
-        }
-        return TT::STRING(string);
+            ch => return Err(LexError::new(format!("unknown escape sequence '\\{}'", ch), self.span(start, self.cur_idx + 1))),
+        };
+        return Ok(escaped);
     }
 
-    fn build_number(&mut self, mut cur_char: char) -> TT {
+    /// Reads a number literal: `0x`/`0o`/`0b` integers, or decimals with an optional fraction
+    /// and exponent. Digits may be separated by `_`. The text is kept as written and
+    /// converted when the `Number` node is evaluated.
+    fn build_number(&mut self, cur_char: char) -> Result<TT, LexError> {
+        let start = self.cur_idx;
         let mut number = String::from(cur_char);
-        let mut is_float = false;
-        while self.advance() {
-            cur_char = self.get_cur_char();
-            if cur_char == '.' {
-                if !is_float {
-                    is_float = true;
-                } else {
-                    break;
-                }
-            } else if cur_char < '0' || cur_char > '9' {
+        let radix = match (cur_char, self.peek_char()) {
+            ('0', Some('x' | 'X')) => 16,
+            ('0', Some('o' | 'O')) => 8,
+            ('0', Some('b' | 'B')) => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            self.advance();
+            number.push(self.get_cur_char());
+            if self.take_digits(&mut number, radix) == 0 {
+                return Err(LexError::new(format!("expected digits after '{}'", number), self.span(start, self.cur_idx + 1)));
+            }
+            return Ok(TT::NUMBER(number));
+        }
+
+        if cur_char != '.' {
+            self.take_digits(&mut number, 10);
+        }
+        if cur_char == '.' || (self.peek_char() == Some('.') && self.peek_nth(2).map_or(false, |c| c.is_ascii_digit())) {
+            if cur_char != '.' {
+                self.advance();
+                number.push('.');
+            }
+            self.take_digits(&mut number, 10);
+        }
+        if let Some('e' | 'E') = self.peek_char() {
+            self.advance();
+            number.push(self.get_cur_char());
+            if let Some(sign @ ('+' | '-')) = self.peek_char() {
+                self.advance();
+                number.push(sign);
+            }
+            if self.take_digits(&mut number, 10) == 0 {
+                return Err(LexError::new("expected digits in exponent".to_owned(), self.span(start, self.cur_idx + 1)));
+            }
+        }
+        return Ok(TT::NUMBER(number));
+    }
+
+    /// Appends the digits (and `_` separators) that follow the current char, returning how
+    /// many digits were read.
+    fn take_digits(&mut self, number: &mut String, radix: u32) -> usize {
+        let mut count = 0;
+        while let Some(ch) = self.peek_char() {
+            if ch.is_digit(radix) {
+                count += 1;
+            } else if ch != '_' {
                 break;
             }
-            number += cur_char.to_string().as_str();
+            self.advance();
+            number.push(ch);
         }
-        //will advance too far if we dont go back
-        self.back();
-        return TT::NUMBER(number);
+        return count;
     }
 
     fn get_cur_char(&self) -> char {
-        return self.text.as_bytes()[self.cur_idx] as char;
+        return self.chars[self.cur_idx];
+    }
+
+    fn peek_char(&self) -> Option<char> {
+        return self.peek_nth(1);
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        return self.chars.get(self.cur_idx + n).copied();
     }
 
     fn advance(&mut self) -> bool {
         self.cur_idx += 1;
-        return self.cur_idx < self.text.len();
+        return self.cur_idx < self.chars.len();
     }
 
     fn back(&mut self) {
         self.cur_idx -= 1;
     }
 }
+
+/// Identifiers start with `_` or a Unicode letter (XID_Start)...
+fn is_ident_start(ch: char) -> bool {
+    return ch == '_' || unicode_ident::is_xid_start(ch);
+}
+
+/// ...and continue with letters, digits and `_` (XID_Continue).
+fn is_ident_continue(ch: char) -> bool {
+    return unicode_ident::is_xid_continue(ch);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::render_snippet;
+
+    fn lex(text: &str) -> Vec<Token> {
+        return Lexer::new(text.to_owned()).parse().expect("source should lex");
+    }
+
+    #[test]
+    fn string_literals_keep_utf8() {
+        let toks = lex("\"héllo wörld 😀 日本\"");
+        assert_eq!(toks.len(), 1);
+        match &toks[0].tt {
+            TT::STRING(s) => assert_eq!(s, "héllo wörld 😀 日本"),
+            tt => panic!("expected a string, got {:?}", tt),
+        }
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let toks = lex("café + 日本 * _ñ2");
+        let names: Vec<String> = toks.iter().filter_map(|tok| match &tok.tt {
+            TT::IDENT(name) => Some(name.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(names, vec!["café", "日本", "_ñ2"]);
+        assert!(matches!(toks[1].tt, TT::PLUS));
+        assert!(matches!(toks[3].tt, TT::MUL));
+    }
+
+    #[test]
+    fn spans_after_multibyte_chars() {
+        let toks = lex("\"é\" x\n日本 = 1");
+        // `"é"` is 4 bytes but 3 chars
+        assert_eq!(toks[0].span, Span::new(0, 4, 1, 1));
+        assert_eq!(toks[1].span, Span::new(5, 6, 1, 5));
+        // each of 日 and 本 is 3 bytes
+        assert_eq!(toks[2].span, Span::new(7, 13, 2, 1));
+        assert_eq!(toks[3].span, Span::new(14, 15, 2, 4));
+        assert_eq!(toks[4].span, Span::new(16, 17, 2, 6));
+    }
+
+    #[test]
+    fn comments_are_kept_as_trivia() {
+        let source = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/exponents.escr"))
+            .expect("example should exist");
+        let mut lexer = Lexer::new(source.clone());
+        let toks = lexer.parse().expect("example should lex");
+        let trivia = lexer.get_trivia();
+        assert!(!trivia.is_empty());
+        for comment in &trivia {
+            assert_eq!(&source[comment.span.start..comment.span.end], comment.text);
+            assert!(comment.text.starts_with('#') || comment.text.starts_with("//") || comment.text.starts_with("/*"));
+            // comments and tokens never overlap
+            assert!(toks.iter().all(|tok| tok.span.end <= comment.span.start || tok.span.start >= comment.span.end));
+        }
+        // every `#` comment line of the example is there, word for word
+        let lines: Vec<&str> = source.lines().map(str::trim).filter(|line| line.starts_with('#')).collect();
+        let found = trivia.iter().filter(|comment| lines.contains(&comment.text.trim_end())).count();
+        assert_eq!(found, lines.len());
+    }
+
+    #[test]
+    fn nested_block_comment_is_one_trivia() {
+        let mut lexer = Lexer::new("1 /* a /* b */ c */ + // end\n2".to_owned());
+        let toks = lexer.parse().expect("source should lex");
+        assert_eq!(toks.len(), 3);
+        let texts: Vec<String> = lexer.get_trivia().into_iter().map(|comment| comment.text).collect();
+        assert_eq!(texts, vec!["/* a /* b */ c */", "// end"]);
+    }
+
+    #[test]
+    fn carets_line_up_under_multibyte_text() {
+        let source = "var 名前 = \"ü\" + 日本;";
+        let toks = lex(source);
+        let span = toks[5].span;
+        let text = render_snippet(source, &span, "oops");
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "error: oops");
+        assert_eq!(lines[1], " --> 1:16");
+        assert_eq!(lines[3], "1 | var 名前 = \"ü\" + 日本;");
+        assert_eq!(lines[4], "  |                ^^");
+    }
+}
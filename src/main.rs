@@ -2,22 +2,36 @@ mod interpreter;
 mod lexer;
 mod nodes;
 mod parser;
+mod span;
 mod symbol_table;
 mod tokens;
 mod types;
 mod builtin_functions;
-
-mod util;
+mod error;
 
 
 // use nodes::Node;
 
-use std::{collections::HashMap, env};
+use std::env;
+
+use symbol_table::Env;
 
-use symbol_table::SymbolTable;
 
+/// Stack size of the thread the program runs on, enough for `types::MAX_CALL_DEPTH` nested
+/// escr calls even in a debug build. Only the part that is used gets committed.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
 
 fn main() {
+    let runner = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .expect("could not start the interpreter thread");
+    if runner.join().is_err() {
+        std::process::exit(101);
+    }
+}
+
+fn run() {
     let args: Vec<String> = env::args().collect();
     let mut text = String::from("");
     let mut found_text = false;
@@ -36,20 +50,32 @@ fn main() {
         }
     }
     // println!("{}", text);
-    let mut l = lexer::Lexer::new(text);
-    let toks = l.parse();
+    let mut l = lexer::Lexer::new(text.clone());
+    let toks = match l.parse() {
+        Ok(toks) => toks,
+        Err(err) => {
+            eprintln!("{}", err.render(&text));
+            std::process::exit(1);
+        }
+    };
     // println!("{:?}", toks);
     let mut p = parser::Parser::new(toks);
-    let nodes = p.parse();
+    let nodes = match p.parse() {
+        Ok(nodes) => nodes,
+        Err(errors) => {
+            for err in &errors {
+                eprintln!("{}\n", err.render(&text));
+            }
+            eprintln!("{} syntax error(s) found", errors.len());
+            std::process::exit(1);
+        }
+    };
     // let mut int = interpreter::Interpreter::new();
     // int.interpret(nodes);
     // println!("{}", nodes.repr(0));
-    let mut symbols: HashMap<String, types::Type> = HashMap::new();
-    let _res = interpreter::interpret(
-        nodes,
-        &mut SymbolTable {
-            symbols: &mut symbols,
-            parent: None,
-        },
-    );
+    let res = interpreter::interpret(nodes, &Env::new(None));
+    if let Err(err) = res {
+        eprintln!("{}", err.render(&text));
+        std::process::exit(1);
+    }
 }
@@ -1,57 +1,55 @@
 use std::collections::HashMap;
-use std::ops::Deref;
+use std::rc::Rc;
+
+use indexmap::IndexMap;
+use num_bigint::BigInt;
 
 use crate::{lexer, parser};
-use crate::symbol_table::SymbolTable;
-use crate::tokens::TT;
-use crate::types::{Number, Type};
+use crate::error::RuntimeError;
+use crate::span::Span;
+use crate::symbol_table::Env;
+use crate::tokens::{Keyword, TT};
+use crate::types::{PathSegment, Type};
 
-use crate::util::ternary;
 
-macro_rules! compare_numbertype {
+/// Applies an ordering operator using `Type::compare`; unordered values (NaN) compare false.
+macro_rules! compare_types {
     ($left:ident $op:tt $right:ident) => {
-        match $left.into_number() {
-               Number::Int(l) => {
-                   match $right.into_number() {
-                       Number::Int(r) => {
-                           let ans = ternary!(1, if(l $op r) else 0);
-                           Type::Int(ans)
-                       },
-                       Number::Float(r) => {
-                           let ans = ternary!(1, if (l $op r as i64) else 0);
-                           Type::Int(ans)
-                       }
-                   }
-                },
-                Number::Float(l) => {
-                    match $right.into_number() {
-                        Number::Float(r) => {
-                            let ans = ternary!(1, if(l $op r) else 0);
-
-                            Type::Int(ans)
-                        },
-                        Number::Int(r) => {
-                            let ans = ternary!(1, if(l $op r as f64) else 0);
-                            Type::Int(ans)
-                        }
-                    }
-                }
+        match $left.compare($right)? {
+            Some(ord) => Type::Bool(ord $op std::cmp::Ordering::Equal),
+            None => Type::Bool(false),
         }
     };
 }
 
+/// A variable and the path from it to one of its fields or elements.
+type Place = (String, Vec<PathSegment>);
+
+/// An AST node and the span of source it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span,
+}
+
 #[derive(Debug, Clone)]
-pub enum Node {
+pub enum NodeKind {
     Program(Box<Node>),
     Number(String),
+    Bool(bool),
+    Nil,
     List(Vec<Box<Node>>),
+    /// `{key: value, ...}`
+    Map(Vec<(Node, Node)>),
     String(String),
-    Node(Box<Node>),
+    /// A `code ... edoc` block. The body is shared with the `Type::Node` values made from it.
+    Node(Rc<Node>),
     VarAssign(String, Box<Node>),
+    /// A function definition. The body is shared with the `Type::Function` values made from it.
     FuncAssign {
         name: String,
         params: Vec<String>,
-        body: Box<Node>,
+        body: Rc<Node>,
     },
     VarAccess(String),
     Pipe(Box<Node>, Box<Node>),
@@ -65,11 +63,32 @@ pub enum Node {
         code: Box<Node>,
     },
     BinOp(Box<Node>, TT, Box<Node>),
+    /// A chain of two or more comparisons, e.g. `a < b < c`.
+    Comparison {
+        first: Box<Node>,
+        rest: Vec<(TT, Node)>,
+    },
     UnOp(TT, Box<Node>),
-    FunctionCall(Box<Node>, Vec<Node>),
+    /// A call with its positional arguments, then its `name: value` ones.
+    FunctionCall(Box<Node>, Vec<Node>, Vec<(String, Node)>),
     MultiStatement(Vec<Node>),
-    StructCreate{name: String, fields: Vec<String> },
-    VarDottedAccess{left: Box<Node>, ident: String}
+    /// A struct declaration. `defaults` holds the fields declared with a default value,
+    /// and `methods` are `FuncAssign` nodes.
+    StructCreate{name: String, fields: Vec<String>, defaults: Vec<(String, Node)>, methods: Vec<Node> },
+    VarDottedAccess{left: Box<Node>, ident: String},
+    /// `target[index]`
+    Index {
+        target: Box<Node>,
+        index: Box<Node>,
+    },
+    /// `target[from:to]`, either bound may be left out.
+    Slice {
+        target: Box<Node>,
+        from: Option<Box<Node>>,
+        to: Option<Box<Node>>,
+    },
+    /// Placeholder for a statement that failed to parse.
+    Error,
 }
 
 macro_rules! str_mul {
@@ -82,35 +101,52 @@ macro_rules! str_mul {
 impl Node {
     //TODO: impl repr() method
 
+    pub fn new(kind: NodeKind, span: Span) -> Node {
+        return Node { kind, span };
+    }
+
     pub fn repr(&self, indent: usize) -> String {
-        match self {
-            Node::VarDottedAccess {..} => panic!("VarDottedAccess not implemented for repr()"),
-            Node::StructCreate{ .. } => panic!("Struct create repr()"),
-            // Node::VarReAssign(name, node) => {
-            //     let mut text = "VarReAssign(".to_owned() + "\n";
-            //     text += &(str_mul!("\t" * indent + 1) + &name + "\n");
-            //     text += &(str_mul!("\t" * indent + 1) + &node.repr(indent + 1) + "\n");
-            //     text += &(str_mul!("\t" * indent) + ")");
-            //     return text;
-            // },
-            Node::Node(n) => {
+        match &self.kind {
+            NodeKind::VarDottedAccess { left, ident } => {
+                "VarDottedAccess(".to_owned() + &left.repr(indent) + "." + ident + ")"
+            }
+            NodeKind::Index { target, index } => {
+                "Index(".to_owned() + &target.repr(indent) + "[" + &index.repr(indent) + "])"
+            }
+            NodeKind::Slice { target, from, to } => {
+                let from = from.as_ref().map_or(String::new(), |n| n.repr(indent));
+                let to = to.as_ref().map_or(String::new(), |n| n.repr(indent));
+                "Slice(".to_owned() + &target.repr(indent) + "[" + &from + ":" + &to + "])"
+            }
+            NodeKind::StructCreate{ name, fields, defaults, methods } => {
+                let mut text = "StructCreate(".to_owned() + name + " = " + &fields.join(", ");
+                for (field, default) in defaults {
+                    text += &("; ".to_owned() + field + " = " + &default.repr(indent + 1));
+                }
+                for method in methods {
+                    text += &("; ".to_owned() + &method.repr(indent + 1));
+                }
+                text + ")"
+            }
+            NodeKind::Error => "Error".to_owned(),
+            NodeKind::Node(n) => {
                 String::from("Node(") + &n.repr(indent + 1) + ")"
             }
-            Node::UnOp(op, right) => {
+            NodeKind::UnOp(op, right) => {
                 let mut text = "UnOp(".to_owned();
                 text += &(str_mul!("\t" * indent + 1) + &op.to_string() + "\n");
                 text += &(str_mul!("\t" * indent + 1) + &right.repr(indent + 1) + "\n");
                 text += &(str_mul!["\t" * indent] + ")");
                 return text;
             }
-            Node::While{ condition, code } => {
+            NodeKind::While{ condition, code } => {
                 let mut text = "While(".to_owned();
                 text +=&(str_mul!("\t" * indent + 1) + &condition.repr(indent + 1) + "\n");
                 text += &(str_mul!("\t" * indent + 1) + &code.repr(indent + 1));
                 text += ")";
                 return text;
             }
-            Node::BinOp(left, op, right) => {
+            NodeKind::BinOp(left, op, right) => {
                 let mut text = "BinOp(".to_owned() + "\n";
                 text += &(str_mul!("\t" * indent + 1) + &left.repr(indent + 1) + "\n");
                 text += &(str_mul!("\t" * indent + 1) + &op.to_string() + "\n");
@@ -118,8 +154,18 @@ impl Node {
                 text += &(str_mul!("\t" * indent) + ")");
                 return text;
             }
-            Node::VarAccess(name) => "VarAccess(".to_owned() + &name + ")",
-            Node::FuncAssign{name, params, body} => {
+            NodeKind::Comparison { first, rest } => {
+                let mut text = "Comparison(".to_owned() + "\n";
+                text += &(str_mul!("\t" * indent + 1) + &first.repr(indent + 1) + "\n");
+                for (op, right) in rest {
+                    text += &(str_mul!("\t" * indent + 1) + &op.to_string() + "\n");
+                    text += &(str_mul!("\t" * indent + 1) + &right.repr(indent + 1) + "\n");
+                }
+                text += &(str_mul!("\t" * indent) + ")");
+                return text;
+            }
+            NodeKind::VarAccess(name) => "VarAccess(".to_owned() + &name + ")",
+            NodeKind::FuncAssign{name, params, body} => {
                 let mut text = "FuncAssign(\n".to_owned();
                 text += &(name.to_owned() + "(");
                 for param in params {
@@ -133,7 +179,7 @@ impl Node {
                 text += ")";
                 return text;
             }
-            Node::Pipe(left, right) => {
+            NodeKind::Pipe(left, right) => {
                 let mut text = "Pipe(".to_owned() + "\n";
                 text += &(str_mul!("\t" * indent + 1) + &left.repr(indent + 1) + "\n");
                 text += &(str_mul!("\t" * indent + 1) + "|" + "\n");
@@ -141,24 +187,29 @@ impl Node {
                 text += &(str_mul!("\t" * indent) + ")");
                 return text;
             }
-            Node::VarAssign(name, value) => {
+            NodeKind::VarAssign(name, value) => {
                 let mut text = "VarAssign(".to_owned() + "\n";
                 text += &(str_mul!("\t" * indent + 1) + &name.to_owned() + "\n");
                 text += &(str_mul!("\t" * indent + 1) + &value.repr(indent + 1));
                 return text;
             }
-            Node::FunctionCall(name, params) => {
+            NodeKind::FunctionCall(name, params, named) => {
                 let mut text = "Call(".to_owned() + &name.repr(0) + "(\n";
                 for param in params {
                     text += &str_mul!("\t" * indent + 2);
                     text += &param.repr(indent + 2);
                     text += "\n";
                 }
+                for (param_name, param) in named {
+                    text += &str_mul!("\t" * indent + 2);
+                    text += &(param_name.to_owned() + ": " + &param.repr(indent + 2));
+                    text += "\n";
+                }
                 text += &(str_mul!("\t" * indent + 1) + ")\n");
                 text += &(str_mul!("\t" * indent) + ")");
                 return text;
             }
-            Node::MultiStatement(stmts) => {
+            NodeKind::MultiStatement(stmts) => {
                 let mut text = "MultiStatement(".to_owned() + "\n";
                 for stmnt in stmts {
                     text += &(str_mul!("\t" * indent + 1) + &stmnt.repr(indent + 1));
@@ -167,17 +218,19 @@ impl Node {
                 text += &(str_mul!("\t" * indent) + ")");
                 return text;
             }
-            Node::String(s) => return "String(".to_owned() + &s + ")",
-            Node::Number(x) => {
+            NodeKind::String(s) => return "String(".to_owned() + &s + ")",
+            NodeKind::Bool(b) => return "Bool(".to_owned() + &b.to_string() + ")",
+            NodeKind::Nil => "Nil".to_owned(),
+            NodeKind::Number(x) => {
                 return "Number(".to_owned() + &x.to_string() + ")";
             }
-            Node::Program(p) => {
+            NodeKind::Program(p) => {
                 let mut text = "Program(".to_owned() + "\n";
                 text += &("\t".to_owned().repeat(indent + 1) + &p.repr(indent + 1) + "\n");
                 text += &("\t".to_owned().repeat(indent) + ")");
                 return text;
             }
-            Node::If {
+            NodeKind::If {
                 condition,
                 body,
                 else_body,
@@ -197,7 +250,17 @@ impl Node {
                 text += &(str_mul!("\t" * indent) + ")");
                 return text;
             }
-            Node::List(l) => {
+            NodeKind::Map(entries) => {
+                let mut text = "Map(\n".to_owned();
+                for (key, value) in entries {
+                    text += &str_mul!("\t" * indent + 1);
+                    text += &(key.repr(indent + 1) + ": " + &value.repr(indent + 1));
+                    text += &",\n".to_owned();
+                }
+                text += &(str_mul!("\t" * indent) + ")");
+                return text;
+            }
+            NodeKind::List(l) => {
                 let mut text = "List(\n".to_owned();
                 for item in l {
                     text += &str_mul!("\t" * indent + 1);
@@ -210,178 +273,485 @@ impl Node {
         }
     }
 
-    pub fn visit(&self, env: &mut SymbolTable) -> Type {
-        match self {
-            Node::VarDottedAccess{ left, ident } =>{
-                let left_type = left.visit(env);
-                return left_type.dot_access(ident.to_owned());
-            }
-            Node::StructCreate{ name, fields } => {
-                let r#struct = Type::Struct{name: name.to_owned(), fields: fields.to_owned()};
-                env.symbols.insert(name.to_owned(), r#struct.clone());
-                return r#struct;
-            }
-            Node::List(n) => {
-                let mut items = vec![];
-                for node in n {
-                    items.push(Box::new(node.visit(env)));
-                }
-                return Type::List(items);
-            }
-            Node::Program(n) => return n.visit(env),
-            Node::Node(n) => return Type::Node(n.deref().clone()),
-            Node::MultiStatement(n) => {
-                if n.len() < 1 {
-                    panic!("No nodes");
-                }
-                let mut res: Option<Type> = None;
-                for node in n {
-                    res = Some(node.visit(env));
-                }
-                if let Some(t) = res {
-                    return t;
-                }
-                panic!("No code");
-            }
-            Node::Number(s) => {
-                if s.contains('.') {
-                    if let Ok(n) = s.parse::<f64>() {
-                        return Type::Float(n);
-                    }
-                }
-                if let Ok(n) = s.parse::<i64>() {
-                    return Type::Int(n);
+    pub fn visit(&self, env: &Env) -> Result<Type, RuntimeError> {
+        return self.eval(env).map_err(|err| err.at(self.span));
+    }
+
+    fn eval(&self, env: &Env) -> Result<Type, RuntimeError> {
+        match &self.kind {
+            NodeKind::VarDottedAccess{ left, ident } => self.dotted_access(left, ident, env),
+            NodeKind::Index { target, index } => self.index(target, index, env),
+            NodeKind::Slice { target, from, to } => self.slice(target, from.as_deref(), to.as_deref(), env),
+            NodeKind::StructCreate{ name, fields, defaults, methods } => self.struct_create(name, fields, defaults, methods, env),
+            NodeKind::List(n) => self.list(n, env),
+            NodeKind::Map(entries) => self.map(entries, env),
+            NodeKind::Program(n) => return n.visit(env),
+            NodeKind::Error => Err(RuntimeError::new("Cannot run a statement that failed to parse".to_owned())),
+            NodeKind::Node(n) => return Ok(Type::Node {
+                code: n.clone(),
+                closure: env.clone(),
+            }),
+            NodeKind::MultiStatement(n) => self.multi_statement(n, env),
+            NodeKind::Number(s) => parse_number(s),
+            NodeKind::String(s) => Ok(Type::String(s.to_string())),
+            NodeKind::Bool(b) => Ok(Type::Bool(*b)),
+            NodeKind::Nil => Ok(Type::Nil),
+            NodeKind::VarAssign(name, node) => self.var_assign(name, node, env),
+            NodeKind::VarAccess(name) => match env.clone_item(name) {
+                Some(val) => Ok(val),
+                None => Err(undefined(name)),
+            },
+            NodeKind::FuncAssign { name, params, body } => self.func_assign(name, params, body, env),
+            NodeKind::FunctionCall(name, params, named) => self.call(name, params, named, env),
+            NodeKind::While { condition, code } => self.while_loop(condition, code, env),
+            NodeKind::Pipe(left, right) => self.pipe(left, right, env),
+            NodeKind::If {
+                condition,
+                body,
+                else_body,
+            } => {
+                if condition.visit(env)?.is_truthy() {
+                    body.visit(env)
                 } else {
-                    panic!("Not a number")
+                    if let Some(c) = else_body {
+                        c.visit(env)
+                    } else {
+                        return Ok(Type::Nil);
+                    }
                 }
             }
-            Node::String(s) => Type::String(s.to_string()),
-            // Node::VarReAssign(name, node) => {
-            //     let val = node.visit(env);
-            //     if env.symbols.contains_key(name){
-            //         env.symbols.insert(name.to_owned(), val.clone());
-            //         val
-            //     }
-            //     else {
-            //         panic!("{} is undefined", name);
-            //     }
-            // }
-            Node::VarAssign(name, node) => {
-                let val = node.visit(env);
-                env.symbols.insert(name.to_owned(), val.clone());
-                val
-            }
-            Node::VarAccess(name) => env.clone_item(name),
-            Node::FuncAssign { name, params, body } => {
-                let val = Type::Function {
-                    name: name.to_owned(),
+            NodeKind::BinOp(left, op, right) => self.binop(left, op, right, env),
+            NodeKind::Comparison { first, rest } => self.comparison(first, rest, env),
+            NodeKind::UnOp(op, right) => self.unop(op, right, env),
+        }
+    }
+
+    // the larger arms of `eval` live in their own functions so that they don't bloat its
+    // stack frame, which every level of escr recursion pays for several times over
+    #[inline(never)]
+    fn struct_create(&self, name: &str, fields: &[String], defaults: &[(String, Node)], methods: &[Node], env: &Env) -> Result<Type, RuntimeError> {
+        let mut default_map = HashMap::new();
+        for (field, default) in defaults {
+            default_map.insert(field.to_owned(), default.visit(env)?);
+        }
+        let mut method_map = HashMap::new();
+        for method in methods {
+            if let NodeKind::FuncAssign { name: method_name, params, body } = &method.kind {
+                if fields.contains(method_name) {
+                    return Err(RuntimeError::new(format!("{} has both a field and a method called {}", name, method_name))
+                        .at(method.span));
+                }
+                method_map.insert(method_name.to_owned(), Type::Function {
+                    name: method_name.to_owned(),
                     parameters: params.to_owned(),
-                    code: body.deref().to_owned(),
-                };
-                env.symbols.insert(name.to_owned(), val.clone());
-                val
+                    code: body.clone(),
+                    closure: env.clone(),
+                });
             }
-            Node::FunctionCall(name, params) => {
-                let func = name.visit(env);
-                match &func.access() {
-                    Type::Function { .. } | Type::Node(..) | Type::BuiltinFunction { .. } | Type::Struct { .. } => {
-                        let mut real_params = vec![];
-                        for item in params {
-                            let val = item.visit(env);
-                            real_params.push(val);
-                        }
-                        let rv = func.run(real_params, env);
-                        return rv;
+        }
+        let r#struct = Type::Struct{
+            name: name.to_owned(),
+            fields: fields.to_owned(),
+            defaults: default_map,
+            methods: Rc::new(method_map),
+        };
+        env.define(name, r#struct.clone());
+        return Ok(r#struct);
+    }
+
+    #[inline(never)]
+    fn call(&self, name: &Node, params: &[Node], named: &[(String, Node)], env: &Env) -> Result<Type, RuntimeError> {
+        // `receiver.method(...)` runs with `self` bound to the receiver, and any changes
+        // the method makes to `self` are stored back into it
+        let mut bound_self = None;
+        let func = match &name.kind {
+            NodeKind::VarDottedAccess { left: receiver, ident } => {
+                let (receiver_value, place) = self.receiver(receiver, env)?;
+                match receiver_value.bind_method(ident) {
+                    Some((method, bound)) => {
+                        bound_self = Some((receiver_value, place, bound));
+                        method
                     }
-                    t => panic!("Cannot call {:?}", t),
+                    None => receiver_value.dot_access(ident.to_owned()).map_err(|err| err.at(name.span))?,
                 }
             }
-            Node::While { condition, code } => {
-                let mut res: Type = Type::Int(0);
-                while let Number::Int(x) = condition.visit(env).into_number() {
-                    if x == 0 {
-                        break;
-                    }
-                    res = code.visit(env)
-                }
-                res
+            _ => name.visit(env)?,
+        };
+        let func_name = match &func {
+            Type::Function { name, .. } | Type::BuiltinFunction { name, .. } | Type::Struct { name, .. } => name.to_owned(),
+            Type::Node { .. } => "<code>".to_owned(),
+            t => return Err(RuntimeError::new(format!("Cannot call {}", t.describe()))),
+        };
+        let mut real_params = vec![];
+        for item in params {
+            let val = item.visit(env)?;
+            real_params.push(val);
+        }
+        let mut named_params = vec![];
+        for (param_name, item) in named {
+            named_params.push((param_name.to_owned(), item.visit(env)?));
+        }
+        let res = func.run(real_params, named_params, env)
+            .map_err(|err| err.with_frame(func_name, self.span))?;
+        if let Some((old_self, Some(((root, path), old_root)), bound)) = bound_self {
+            store_self(&old_self, &bound, &old_root, &root, &path, env)?;
+        }
+        return Ok(res);
+    }
+
+    /// Evaluates the receiver of a method call. If it is a place, it is read through the path
+    /// it names, and that path is returned too so `self` can be stored back through it without
+    /// evaluating the indices again, along with the variable's value before the call.
+    fn receiver(&self, receiver: &Node, env: &Env) -> Result<(Type, Option<(Place, Type)>), RuntimeError> {
+        if !receiver.is_place() {
+            return Ok((receiver.visit(env)?, None));
+        }
+        let (root, path) = self.place(receiver, env)?;
+        let root_value = match env.clone_item(&root) {
+            Some(value) => value,
+            None => return Err(undefined(&root).at(receiver.span)),
+        };
+        let value = root_value.get_path(&path).map_err(|err| err.at(receiver.span))?;
+        return Ok((value, Some(((root, path), root_value))));
+    }
+
+    #[inline(never)]
+    fn dotted_access(&self, left: &Node, ident: &str, env: &Env) -> Result<Type, RuntimeError> {
+        let left_type = left.visit(env)?;
+        return left_type.dot_access(ident.to_owned());
+    }
+
+    #[inline(never)]
+    fn index(&self, target: &Node, index: &Node, env: &Env) -> Result<Type, RuntimeError> {
+        let target = target.visit(env)?;
+        let index = index.visit(env)?;
+        return target.index(&index);
+    }
+
+    #[inline(never)]
+    fn multi_statement(&self, nodes: &[Node], env: &Env) -> Result<Type, RuntimeError> {
+        let mut res: Option<Type> = None;
+        for node in nodes {
+            res = Some(node.visit(env)?);
+        }
+        if let Some(t) = res {
+            return Ok(t);
+        }
+        Err(RuntimeError::new("Cannot run an empty block".to_owned()))
+    }
+
+    #[inline(never)]
+    fn var_assign(&self, name: &str, node: &Node, env: &Env) -> Result<Type, RuntimeError> {
+        let val = node.visit(env)?;
+        env.define(name, val.clone());
+        Ok(val)
+    }
+
+    #[inline(never)]
+    fn func_assign(&self, name: &str, params: &[String], body: &Rc<Node>, env: &Env) -> Result<Type, RuntimeError> {
+        let val = Type::Function {
+            name: name.to_owned(),
+            parameters: params.to_owned(),
+            code: body.clone(),
+            closure: env.clone(),
+        };
+        env.define(name, val.clone());
+        Ok(val)
+    }
+
+    #[inline(never)]
+    fn while_loop(&self, condition: &Node, code: &Node, env: &Env) -> Result<Type, RuntimeError> {
+        let mut res: Type = Type::Nil;
+        while condition.visit(env)?.is_truthy() {
+            res = code.visit(env)?
+        }
+        Ok(res)
+    }
+
+    #[inline(never)]
+    fn pipe(&self, left: &Node, right: &Node, env: &Env) -> Result<Type, RuntimeError> {
+        let l = left.visit(env)?;
+        env.define("PIPE", l);
+        right.visit(env)
+    }
+
+    #[inline(never)]
+    fn slice(&self, target: &Node, from: Option<&Node>, to: Option<&Node>, env: &Env) -> Result<Type, RuntimeError> {
+        let target = target.visit(env)?;
+        let from = match from {
+            Some(n) => Some(n.visit(env)?),
+            None => None,
+        };
+        let to = match to {
+            Some(n) => Some(n.visit(env)?),
+            None => None,
+        };
+        return target.slice(from.as_ref(), to.as_ref());
+    }
+
+    #[inline(never)]
+    fn list(&self, nodes: &[Box<Node>], env: &Env) -> Result<Type, RuntimeError> {
+        let mut items = vec![];
+        for node in nodes {
+            items.push(Box::new(node.visit(env)?));
+        }
+        return Ok(Type::List(items));
+    }
+
+    #[inline(never)]
+    fn map(&self, entries: &[(Node, Node)], env: &Env) -> Result<Type, RuntimeError> {
+        let mut map = Type::Map(IndexMap::new());
+        for (key, value) in entries {
+            let key = key.visit(env)?;
+            map.set_path(&[PathSegment::Index(key)], value.visit(env)?)?;
+        }
+        return Ok(map);
+    }
+
+    #[inline(never)]
+    fn binop(&self, left: &Node, op: &TT, right: &Node, env: &Env) -> Result<Type, RuntimeError> {
+        match op {
+            TT::EQ => return self.assign(left, right, env),
+            // `and`/`or` only evaluate the right side when it decides the result
+            TT::KEYWORD(Keyword::And) => {
+                let res = left.visit(env)?.is_truthy() && right.visit(env)?.is_truthy();
+                return Ok(Type::Bool(res));
             }
-            Node::Pipe(left, right) => {
-                let l = left.visit(env);
-                env.symbols.insert("PIPE".to_string(), l);
-                right.visit(env)
+            TT::KEYWORD(Keyword::Or) => {
+                let res = left.visit(env)?.is_truthy() || right.visit(env)?.is_truthy();
+                return Ok(Type::Bool(res));
             }
-            Node::If {
-                condition,
-                body,
-                else_body,
-            } => {
-                let cond_val = condition.visit(env).into_number();
+            _ => {}
+        }
+        let l = left.visit(env)?;
+        let r = right.visit(env)?;
+        binary_op(&l, op, &r, self.span)
+    }
 
-                if let Number::Int(x) = cond_val {
-                    if x != 0 {
-                        body.visit(env)
-                    } else {
-                        if let Some(c) = else_body {
-                            c.visit(env)
-                        } else {
-                            return Type::Int(0);
-                        }
-                    }
-                } else {
-                    return Type::Int(0);
-                }
+    #[inline(never)]
+    fn comparison(&self, first: &Node, rest: &[(TT, Node)], env: &Env) -> Result<Type, RuntimeError> {
+        let mut l = first.visit(env)?;
+        for (op, right) in rest {
+            let r = right.visit(env)?;
+            if !binary_op(&l, op, &r, self.span)?.is_truthy() {
+                return Ok(Type::Bool(false));
             }
-            Node::BinOp(left, op, right) => {
-                let mut l = left.visit(env);
-                let r = right.visit(env);
-                let ans = match op {
-                    TT::MUL => l.mul(&r),
-                    TT::DIV => l.div(&r),
-                    TT::MINUS => l.sub(&r),
-                    TT::PLUS => l.add(&r),
-                    TT::GT => compare_numbertype!(l > r),
-                    TT::LT => compare_numbertype!(l < r),
-                    TT::LE => compare_numbertype!(l <= r),
-                    TT::GE => compare_numbertype!(l >= r),
-                    TT::EQEQ => compare_numbertype!(l == r),
-                    TT::EQ => {
-                        match left.deref() {
-                            Node::VarDottedAccess{ident, ..} => {
-                                let mut left_copy = *(left.clone());
-                                while let Node::VarDottedAccess{left: l, ..} = left_copy {
-                                    left_copy = *l;
-                                }
-                                if let Node::VarAccess(name) = left_copy {
-                                    let mut s = env.symbols.remove(&name).unwrap();
-                                    let val = s.setattr(ident, r);
-                                    env.symbols.insert(name, s);
-                                    val
-                                }
-                                else {
-                                    panic!("Weird expression");
-                                }
-                            }
-                            Node::VarAccess(ident) => {
-                                env.symbols.insert(ident.to_owned(), r.clone());
-                                r
-                            }
-                            _ => panic!("Cannot assign to {:?}", left),
-                        }
-                    }
-                    _ => todo!("[BinOpNode] {:?} Not yet implemented", op),
-                };
-                ans
+            l = r;
+        }
+        Ok(Type::Bool(true))
+    }
+
+    #[inline(never)]
+    fn unop(&self, op: &TT, right: &Node, env: &Env) -> Result<Type, RuntimeError> {
+        let r = right.visit(env)?;
+        match op {
+            TT::MINUS if r.is_numeric() => r.mul(&Type::Int(-1)),
+            TT::MINUS => Err(RuntimeError::new(format!("Cannot negate {}", r.type_name()))),
+            TT::PLUS => Ok(r),
+            TT::KEYWORD(Keyword::Not) => Ok(Type::Bool(!r.is_truthy())),
+            _ => Err(RuntimeError::new(format!("{} is not a unary operator", op.to_string())))
+        }
+    }
+
+    /// Whether the node names something that can be assigned to: a variable, or fields and
+    /// elements reached from one.
+    fn is_place(&self) -> bool {
+        match &self.kind {
+            NodeKind::VarAccess(..) => true,
+            NodeKind::VarDottedAccess { left, .. } | NodeKind::Index { target: left, .. } => left.is_place(),
+            _ => false,
+        }
+    }
+
+    /// Evaluates `left = right`.
+    #[inline(never)]
+    fn assign(&self, left: &Node, right: &Node, env: &Env) -> Result<Type, RuntimeError> {
+        let r = right.visit(env)?;
+        return self.assign_to(left, r, env);
+    }
+
+    /// Stores the already evaluated `r` into the place `left` names.
+    #[inline(never)]
+    fn assign_to(&self, left: &Node, r: Type, env: &Env) -> Result<Type, RuntimeError> {
+        match &left.kind {
+            NodeKind::VarAccess(ident) => {
+                if !env.assign(ident, r.clone()) {
+                    return Err(RuntimeError::new(format!(
+                        "{} is not defined in this scope, use 'var {} = ...' to declare it", ident, ident
+                    )));
+                }
+                Ok(r)
             }
-            Node::UnOp(op, right) => {
-                let r = right.visit(env);
-                match op {
-                    TT::MINUS => r.mul(&Type::Int(-1)),
-                    TT::PLUS => r,
-                    _ => panic!("{:?} not a valid unary op", op)
+            NodeKind::VarDottedAccess { .. } | NodeKind::Index { .. } => {
+                let (name, path) = self.place(left, env)?;
+                match env.set_path(&name, &path, r.clone()) {
+                    Some(res) => res?,
+                    None => return Err(undefined(&name)),
                 }
+                Ok(r)
+            }
+            _ => Err(RuntimeError::new("Cannot assign to this expression".to_owned())),
+        }
+    }
+
+    /// The variable a field or element access starts from, and the path from it to the part
+    /// `left` names. The indices are evaluated left to right.
+    fn place(&self, left: &Node, env: &Env) -> Result<Place, RuntimeError> {
+        // walk down to the variable, then build the path back up from it
+        let mut steps = vec![];
+        let mut target = left;
+        while let NodeKind::VarDottedAccess { left: inner, .. } | NodeKind::Index { target: inner, .. } = &target.kind {
+            steps.push(target);
+            target = inner;
+        }
+        let name = match &target.kind {
+            NodeKind::VarAccess(name) => name,
+            _ => return Err(RuntimeError::new("Can only assign to fields and elements of a variable".to_owned())),
+        };
+        let mut path = vec![];
+        for step in steps.iter().rev() {
+            match &step.kind {
+                NodeKind::VarDottedAccess { ident, .. } => path.push(PathSegment::Field(ident.to_owned())),
+                NodeKind::Index { index, .. } => path.push(PathSegment::Index(index.visit(env)?)),
+                _ => unreachable!(),
             }
-            _ => panic!("{:?} not impl", self),
         }
+        return Ok((name.to_owned(), path));
+    }
+}
+
+/// Stores the `self` a method ended with back into the place its receiver was read from. Nothing
+/// is stored if the method left `self` as it was, or if it assigned to the receiver's variable
+/// itself, so that assignment is kept.
+fn store_self(old_self: &Type, bound: &Env, old_root: &Type, root: &str, path: &[PathSegment], env: &Env) -> Result<(), RuntimeError> {
+    let new_self = match bound.clone_item("self") {
+        Some(new_self) if !new_self.equals(old_self) => new_self,
+        _ => return Ok(()),
+    };
+    match env.clone_item(root) {
+        Some(value) if value.equals(old_root) => {}
+        _ => return Ok(()),
+    }
+    return match env.set_path(root, path, new_self) {
+        Some(res) => res,
+        None => Err(undefined(root)),
+    };
+}
+
+fn undefined(name: &str) -> RuntimeError {
+    return RuntimeError::new(format!("{} is not defined in this scope", name));
+}
+
+/// The struct method that overloads `op`. Comparisons other than `==` and `!=` all go through `lt`.
+fn operator_method(op: &TT) -> Option<&'static str> {
+    let name = match op {
+        TT::PLUS => "add",
+        TT::MINUS => "sub",
+        TT::MUL => "mul",
+        TT::DIV => "div",
+        TT::IDIV => "idiv",
+        TT::MOD => "mod",
+        TT::POW => "pow",
+        TT::EQEQ | TT::NE => "eq",
+        TT::LT | TT::LE | TT::GT | TT::GE => "lt",
+        _ => return None,
+    };
+    return Some(name);
+}
+
+/// Runs the struct method overloading `op`, if there is one.
+///
+/// Arithmetic only looks at the left operand. `==` and comparisons also look at the right one,
+/// swapping the operands (`5 < v` becomes `v > 5`). `!=` is the negation of `eq`, and `<=`, `>`
+/// and `>=` are worked out from `lt` and `==`.
+fn overloaded_op(l: &Type, op: &TT, r: &Type, span: Span) -> Result<Option<Type>, RuntimeError> {
+    let name = match operator_method(op) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let call = |receiver: &Type, arg: &Type| -> Result<Option<Type>, RuntimeError> {
+        match receiver.call_method(name, vec![arg.clone()]) {
+            Some(res) => Ok(Some(res.map_err(|err| err.with_frame(format!("{}.{}", receiver.type_name(), name), span))?)),
+            None => Ok(None),
+        }
+    };
+    let flipped = match op {
+        TT::LT => TT::GT,
+        TT::LE => TT::GE,
+        TT::GT => TT::LT,
+        TT::GE => TT::LE,
+        _ => op.clone(),
+    };
+    let (receiver, op, arg) = if l.bind_method(name).is_some() {
+        (l, op.clone(), r)
+    } else if (name == "eq" || name == "lt") && r.bind_method(name).is_some() {
+        (r, flipped, l)
+    } else {
+        return Ok(None);
+    };
+    let res = match op {
+        TT::EQEQ => call(receiver, arg)?.map(|res| Type::Bool(res.is_truthy())),
+        TT::NE => call(receiver, arg)?.map(|res| Type::Bool(!res.is_truthy())),
+        TT::LT => call(receiver, arg)?.map(|res| Type::Bool(res.is_truthy())),
+        TT::GE => call(receiver, arg)?.map(|res| Type::Bool(!res.is_truthy())),
+        TT::LE | TT::GT => {
+            let less = call(receiver, arg)?.map_or(false, |res| res.is_truthy());
+            let equal = binary_op(receiver, &TT::EQEQ, arg, span)?.is_truthy();
+            Some(Type::Bool(if let TT::LE = op { less || equal } else { !less && !equal }))
+        }
+        _ => call(receiver, arg)?,
+    };
+    return Ok(res);
+}
+
+/// Applies an arithmetic or comparison operator to two evaluated operands.
+fn binary_op(l: &Type, op: &TT, r: &Type, span: Span) -> Result<Type, RuntimeError> {
+    if let Some(res) = overloaded_op(l, op, r, span)? {
+        return Ok(res);
+    }
+    let ans = match op {
+        TT::MUL => l.mul(r)?,
+        TT::DIV => l.div(r)?,
+        TT::IDIV => l.idiv(r)?,
+        TT::MOD => l.rem(r)?,
+        TT::POW => l.pow(r)?,
+        TT::MINUS => l.sub(r)?,
+        TT::PLUS => l.add(r)?,
+        TT::GT => compare_types!(l > r),
+        TT::LT => compare_types!(l < r),
+        TT::LE => compare_types!(l <= r),
+        TT::GE => compare_types!(l >= r),
+        TT::EQEQ => Type::Bool(l.equals(r)),
+        TT::NE => Type::Bool(!l.equals(r)),
+        _ => return Err(RuntimeError::new(format!("{} is not a binary operator", op.to_string()))),
+    };
+    Ok(ans)
+}
+
+/// Converts number literal text from the lexer into an `Int` or `Float`.
+fn parse_number(text: &str) -> Result<Type, RuntimeError> {
+    let digits = text.replace('_', "");
+    let radix = match digits.get(..2) {
+        Some("0x" | "0X") => 16,
+        Some("0o" | "0O") => 8,
+        Some("0b" | "0B") => 2,
+        _ => 10,
+    };
+    if radix != 10 {
+        return match BigInt::parse_bytes(digits[2..].as_bytes(), radix) {
+            Some(n) => Ok(Type::from_big(n)),
+            None => Err(RuntimeError::new(format!("{} is not a number", text))),
+        };
+    }
+    if digits.contains(|c| c == '.' || c == 'e' || c == 'E') {
+        return match digits.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Type::Float(n)),
+            Ok(_) => Err(RuntimeError::new(format!("float literal {} is out of range", text))),
+            Err(_) => Err(RuntimeError::new(format!("{} is not a number", text))),
+        };
+    }
+    match digits.parse::<BigInt>() {
+        Ok(n) => Ok(Type::from_big(n)),
+        Err(_) => Err(RuntimeError::new(format!("{} is not a number", text))),
     }
 }
@@ -1,168 +1,392 @@
-use crate::nodes::Node;
-use crate::tokens::Keyword;
+use std::rc::Rc;
+
+use crate::error::ParseError;
+use crate::nodes::{Node, NodeKind};
+use crate::span::Span;
+use crate::tokens::{Keyword, Token};
 // use crate::nodes::{
 // self, BinOpNode, FuncAssignNode, FunctionCallNode, IfNode, MultiStatementNode, ProgramNode,
 // VarAssignNode, WhileNode,
 // };
-use crate::util::{str, String_vec};
 use crate::{nodes, tokens::TT};
 
 pub struct Parser {
-    tokens: Vec<TT>,
+    tokens: Vec<Token>,
     cur_idx: usize,
-    endblock_keyewords: Vec<String>,
+    endblock_keyewords: Vec<Keyword>,
+    errors: Vec<ParseError>,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<TT>) -> Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
         return Parser {
             tokens,
             cur_idx: 0,
-            endblock_keyewords: String_vec!["done", "fi", "rav"],
+            endblock_keyewords: vec![Keyword::Fi, Keyword::Else, Keyword::Rav, Keyword::End, Keyword::Edoc],
+            errors: vec![],
         };
     }
 
-    pub fn parse(&mut self) -> nodes::Node {
-        return self.program();
+    /// Parses the whole program, failing with every syntax error found.
+    pub fn parse(&mut self) -> Result<nodes::Node, Vec<ParseError>> {
+        let (program, errors) = self.parse_partial();
+        if errors.is_empty() {
+            return Ok(program);
+        }
+        return Err(errors);
+    }
+
+    /// Parses the whole program, recovering from syntax errors.
+    ///
+    /// Statements that failed to parse are replaced by `NodeKind::Error` in the returned tree.
+    pub fn parse_partial(&mut self) -> (nodes::Node, Vec<ParseError>) {
+        let program = self.program();
+        return (program, std::mem::take(&mut self.errors));
     }
 
     fn get_cur_tok(&self) -> Option<TT> {
         if self.cur_idx < self.tokens.len() {
-            return Some(self.tokens[self.cur_idx].clone());
+            return Some(self.tokens[self.cur_idx].tt.clone());
         }
         return None;
     }
 
-    fn literal(&mut self) -> nodes::Node {
-        match self.get_cur_tok() {
-            Some(TT::KEYWORD(Keyword::Code)) => {
+    /// Span of the current token, or an empty span just past the last token at end of input.
+    fn cur_span(&self) -> Span {
+        if self.cur_idx < self.tokens.len() {
+            return self.tokens[self.cur_idx].span;
+        }
+        match self.tokens.last() {
+            Some(tok) => Span::new(tok.span.end, tok.span.end, tok.span.line, tok.span.col + tok.span.end - tok.span.start),
+            None => Span::default(),
+        }
+    }
+
+    /// Span from `start` up to the end of the most recently consumed token.
+    fn span_from(&self, start: Span) -> Span {
+        if self.cur_idx > 0 && self.cur_idx <= self.tokens.len() {
+            return start.to(&self.tokens[self.cur_idx - 1].span);
+        }
+        return start;
+    }
+
+    /// Builds a node spanning from `start` to the last consumed token.
+    fn make(&self, kind: NodeKind, start: Span) -> Node {
+        return Node::new(kind, self.span_from(start));
+    }
+
+    /// Error saying `what` was expected where the current token is.
+    fn expected(&self, what: &str) -> ParseError {
+        return ParseError::new(what, self.get_cur_tok(), self.cur_span());
+    }
+
+    /// Consumes `keyword` or fails with a hint pointing back at the construct it closes.
+    fn expect_keyword(&mut self, keyword: Keyword, hint: &str) -> Result<(), ParseError> {
+        if let Some(TT::KEYWORD(kwd)) = self.get_cur_tok() {
+            if kwd == keyword {
                 self.advance();
-                let res = Node::Node(Box::new(self.multi_statement()));
-                if let Some(TT::KEYWORD(Keyword::Edoc)) = self.get_cur_tok() {
+                return Ok(());
+            }
+        }
+        return Err(self.expected(&format!("'{}'", keyword.as_str())).with_hint(hint));
+    }
+
+    /// True if the current token closes the enclosing block, so a statement list must stop there.
+    fn at_block_end(&self) -> bool {
+        match self.get_cur_tok() {
+            Some(TT::KEYWORD(kwd)) => self.endblock_keyewords.contains(&kwd),
+            Some(TT::RPAREN) | None => true,
+            _ => false,
+        }
+    }
+
+    /// Parses `item ("," item)* ","?` followed by `close`, which is consumed. The list may be empty.
+    fn comma_separated<T>(
+        &mut self,
+        close: TT,
+        hint: &str,
+        mut item: impl FnMut(&mut Parser) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        let is_close = |tok: &Option<TT>| matches!(tok, Some(t) if std::mem::discriminant(t) == std::mem::discriminant(&close));
+        let mut items = vec![];
+        while !is_close(&self.get_cur_tok()) {
+            if self.get_cur_tok().is_none() {
+                return Err(self.expected(&format!("'{}'", close.to_string())).with_hint(hint));
+            }
+            items.push(item(self)?);
+            match self.get_cur_tok() {
+                Some(TT::COMMA) => {
                     self.advance();
-                    return res;
                 }
-                panic!("Expected 'edoc' to end node literal");
+                ref tok if is_close(tok) => {}
+                _ => return Err(self.expected(&format!("',' or '{}'", close.to_string())).with_hint(hint)),
             }
-            Some(TT::LBRACKET) => {
-                self.advance();
-                let mut items: Vec<Box<nodes::Node>> = vec![Box::new(self.statement())];
-                while let Some(TT::COMMA) = self.get_cur_tok() {
-                    self.advance();
-                    items.push(Box::new(self.statement()));
+        }
+        self.advance();
+        return Ok(items);
+    }
+
+    /// Skips tokens after a syntax error until the next `;` or the keyword closing the
+    /// enclosing block, stepping over any nested blocks on the way.
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+        while let Some(tok) = self.get_cur_tok() {
+            match tok {
+                TT::SEMI if depth == 0 => return,
+                TT::KEYWORD(Keyword::If | Keyword::While | Keyword::Code | Keyword::Struct) => depth += 1,
+                TT::KEYWORD(Keyword::Var) => {
+                    let is_func = matches!(self.tokens.get(self.cur_idx + 2), Some(Token { tt: TT::LPAREN, .. }));
+                    if is_func {
+                        depth += 1;
+                    }
                 }
-                match self.get_cur_tok() {
-                    Some(TT::RBRACKET) => {
-                        self.advance();
-                        nodes::Node::List(items)
+                TT::KEYWORD(Keyword::Fi | Keyword::End | Keyword::Edoc | Keyword::Rav) => {
+                    if depth == 0 {
+                        return;
                     }
-                    _ => panic!("List must end with ]"),
+                    depth -= 1;
                 }
+                TT::KEYWORD(Keyword::Else) if depth == 0 => return,
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    fn literal(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        match self.get_cur_tok() {
+            Some(TT::KEYWORD(Keyword::Code)) => {
+                self.advance();
+                let res = NodeKind::Node(Rc::new(self.multi_statement()));
+                self.expect_keyword(Keyword::Edoc, "'code' blocks are closed with 'edoc'")?;
+                return Ok(self.make(res, start));
+            }
+            Some(TT::LBRACKET) => {
+                self.advance();
+                let items = self.comma_separated(TT::RBRACKET, "lists must end with ']'", |p| Ok(Box::new(p.statement()?)))?;
+                return Ok(self.make(NodeKind::List(items), start));
+            }
+            Some(TT::LBRACE) => {
+                self.advance();
+                let entries = self.comma_separated(TT::RBRACE, "maps must end with '}'", |p| {
+                    let key = p.statement()?;
+                    if !matches!(p.get_cur_tok(), Some(TT::COLON)) {
+                        return Err(p.expected("':'").with_hint("map entries look like 'key: value'"));
+                    }
+                    p.advance();
+                    return Ok((key, p.statement()?));
+                })?;
+                return Ok(self.make(NodeKind::Map(entries), start));
             }
             Some(TT::NUMBER(x)) => {
                 self.advance();
-                return nodes::Node::Number(x);
+                return Ok(self.make(NodeKind::Number(x), start));
+            }
+            Some(TT::KEYWORD(kwd @ (Keyword::True | Keyword::False))) => {
+                self.advance();
+                return Ok(self.make(NodeKind::Bool(kwd == Keyword::True), start));
+            }
+            Some(TT::KEYWORD(Keyword::Nil)) => {
+                self.advance();
+                return Ok(self.make(NodeKind::Nil, start));
             }
             Some(TT::STRING(s)) => {
                 self.advance();
-                return nodes::Node::String(s);
+                return Ok(self.make(NodeKind::String(s), start));
             }
             Some(TT::IDENT(name)) => {
                 self.advance();
-                return nodes::Node::VarAccess(name);
+                return Ok(self.make(NodeKind::VarAccess(name), start));
             }
-            _ => panic!("Curr toke is none"),
+            _ => Err(self.expected("an expression")),
         }
     }
 
-    fn atom(&mut self) -> nodes::Node {
+    fn atom(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
         match self.get_cur_tok() {
             Some(TT::IDENT(i)) => {
                 self.advance();
-                let ident = nodes::Node::VarAccess(i);
+                let ident = self.make(NodeKind::VarAccess(i), start);
                 match self.get_cur_tok() {
                     Some(TT::EQ) => {
                         self.advance();
-                        return nodes::Node::BinOp(Box::new(ident), TT::EQ, Box::new(self.statement()));
+                        let value = self.statement()?;
+                        return Ok(self.make(NodeKind::BinOp(Box::new(ident), TT::EQ, Box::new(value)), start));
                     }
-                    _ => ident,
+                    _ => Ok(ident),
                 }
             }
             _ => self.literal(),
         }
     }
 
-    fn factor(&mut self) -> nodes::Node {
-        let mut left;
+    /// True if the `(` at the current token has a matching `)` somewhere after it.
+    fn has_closing_paren(&self) -> bool {
+        let mut depth = 0;
+        for tok in &self.tokens[self.cur_idx..] {
+            match tok.tt {
+                TT::LPAREN => depth += 1,
+                TT::RPAREN if depth == 1 => return true,
+                TT::RPAREN => depth -= 1,
+                _ => {}
+            }
+        }
+        return false;
+    }
+
+    fn factor(&mut self) -> Result<nodes::Node, ParseError> {
+        let left;
         if let Some(TT::LPAREN) = self.get_cur_tok() {
+            let open = self.cur_span();
+            if !self.has_closing_paren() {
+                // the group can't end anywhere, so stop it at the first ';' and let the
+                // statements after it parse normally
+                self.advance();
+                self.statement()?;
+                return Err(ParseError::unclosed("(", ")", open));
+            }
             self.advance();
             left = self.multi_statement();
             if let Some(TT::RPAREN) = self.get_cur_tok() {
                 self.advance();
             }
             else{
-                panic!("Expected ')'")
+                return Err(self.expected("')'").with_hint("unclosed '('"));
             }
         }
         else {
-            left = self.atom();
+            left = self.atom()?;
         }
 
-        while let Some(TT::DOT) = self.get_cur_tok(){
-            self.advance();
-            if let Some(TT::IDENT(right)) = self.get_cur_tok(){
-                left = nodes::Node::VarDottedAccess{left: Box::new(left), ident: right};
-                self.advance();
-            }
-            else {
-                break;
+        return Ok(left);
+    }
+
+    /// A factor followed by any number of `.field` accesses, `(args)` calls and `[index]` or
+    /// `[start:end]` subscripts. A field or subscript followed by `=` is an assignment to it.
+    fn call(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        let mut left = self.factor()?;
+        loop {
+            match self.get_cur_tok() {
+                Some(TT::DOT) => {
+                    self.advance();
+                    if let Some(TT::IDENT(right)) = self.get_cur_tok(){
+                        self.advance();
+                        left = self.make(NodeKind::VarDottedAccess{left: Box::new(left), ident: right}, start);
+                    }
+                    else {
+                        return Err(self.expected("a field name after '.'"));
+                    }
+                }
+                Some(TT::LPAREN) => {
+                    self.advance();
+                    let mut seen_named = false;
+                    let args = self.comma_separated(TT::RPAREN, "unclosed argument list", |p| {
+                        let arg = p.argument(seen_named)?;
+                        seen_named = arg.0.is_some();
+                        return Ok(arg);
+                    })?;
+                    let mut nodes = vec![];
+                    let mut named = vec![];
+                    for (name, value) in args {
+                        match name {
+                            Some(name) => named.push((name, value)),
+                            None => nodes.push(value),
+                        }
+                    }
+                    left = self.make(NodeKind::FunctionCall(Box::new(left), nodes, named), start);
+                }
+                Some(TT::LBRACKET) => {
+                    self.advance();
+                    left = self.subscript(left, start)?;
+                }
+                Some(TT::EQ) if matches!(left.kind, NodeKind::Index { .. } | NodeKind::VarDottedAccess { .. }) => {
+                    self.advance();
+                    let value = self.statement()?;
+                    return Ok(self.make(NodeKind::BinOp(Box::new(left), TT::EQ, Box::new(value)), start));
+                }
+                _ => return Ok(left),
             }
         }
+    }
 
-        return left;
+    /// A call argument, either `value` or `name: value`. Once a named argument has been seen
+    /// the rest must be named too.
+    fn argument(&mut self, named_only: bool) -> Result<(Option<String>, nodes::Node), ParseError> {
+        if let (Some(TT::IDENT(name)), Some(TT::COLON)) = (self.get_cur_tok(), self.tokens.get(self.cur_idx + 1).map(|tok| &tok.tt)) {
+            self.advance();
+            self.advance();
+            return Ok((Some(name), self.statement()?));
+        }
+        if named_only {
+            return Err(self.expected("a named argument").with_hint("positional arguments must come before named ones"));
+        }
+        return Ok((None, self.statement()?));
     }
 
-    fn unop(&mut self) -> nodes::Node {
-        let curtok = self.get_cur_tok();
-        let left = match curtok {
-            Some(TT::PLUS) => {
-                self.advance();
-                nodes::Node::UnOp(TT::PLUS, Box::new(self.factor()))
-            }
-            Some(TT::MINUS) => {
+    /// The inside of `target[...]`, after the `[`: either an index or a slice whose bounds may be left out.
+    fn subscript(&mut self, target: Node, start: Span) -> Result<nodes::Node, ParseError> {
+        let mut from = None;
+        if !matches!(self.get_cur_tok(), Some(TT::COLON)) {
+            let index = self.statement()?;
+            if let Some(TT::RBRACKET) = self.get_cur_tok() {
                 self.advance();
-                nodes::Node::UnOp(TT::MINUS, Box::new(self.factor()))
+                return Ok(self.make(NodeKind::Index { target: Box::new(target), index: Box::new(index) }, start));
             }
-            _ => self.factor(),
-        };
-        if let Some(TT::LPAREN) = self.get_cur_tok() {
-            let mut nodes: Vec<nodes::Node> = vec![];
+            from = Some(Box::new(index));
+        }
+        if !matches!(self.get_cur_tok(), Some(TT::COLON)) {
+            return Err(self.expected("']' or ':'").with_hint("unclosed '['"));
+        }
+        self.advance();
+        let mut to = None;
+        if !matches!(self.get_cur_tok(), Some(TT::RBRACKET)) {
+            to = Some(Box::new(self.statement()?));
+        }
+        if !matches!(self.get_cur_tok(), Some(TT::RBRACKET)) {
+            return Err(self.expected("']'").with_hint("unclosed '['"));
+        }
+        self.advance();
+        return Ok(self.make(NodeKind::Slice { target: Box::new(target), from, to }, start));
+    }
+
+    /// `**` binds tighter than unary minus and is right associative, so `-2 ** 2` is `-4`
+    /// and `2 ** 3 ** 2` is `2 ** 9`.
+    fn power(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        let left = self.call()?;
+        if let Some(TT::POW) = self.get_cur_tok() {
             self.advance();
-            while match self.get_cur_tok() {
-                Some(TT::RPAREN) => false,
-                Some(TT::COMMA) => {
-                    self.advance();
-                    true
-                }
-                _ => true,
-            } {
-                nodes.push(self.statement());
+            let right = self.unop()?;
+            return Ok(self.make(NodeKind::BinOp(Box::new(left), TT::POW, Box::new(right)), start));
+        }
+        return Ok(left);
+    }
+
+    fn unop(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        match self.get_cur_tok() {
+            Some(op @ (TT::PLUS | TT::MINUS)) => {
+                self.advance();
+                let right = self.unop()?;
+                Ok(self.make(NodeKind::UnOp(op, Box::new(right)), start))
             }
-            self.advance();
-            return nodes::Node::FunctionCall(Box::new(left), nodes);
-        } else {
-            left
+            _ => self.power(),
         }
     }
 
-    fn term(&mut self) -> nodes::Node {
-        let mut left = self.unop();
+    fn term(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        let mut left = self.unop()?;
         loop {
             if let Some(tok) = self.get_cur_tok() {
                 match tok {
-                    TT::DIV | TT::MUL => {
+                    TT::DIV | TT::MUL | TT::IDIV | TT::MOD => {
                         self.advance();
-                        left = nodes::Node::BinOp(Box::new(left), tok, Box::new(self.unop()));
+                        let right = self.unop()?;
+                        left = self.make(NodeKind::BinOp(Box::new(left), tok, Box::new(right)), start);
                     }
                     _ => {
                         break;
@@ -172,17 +396,19 @@ impl Parser {
                 break;
             }
         }
-        return left;
+        return Ok(left);
     }
 
-    fn arith(&mut self) -> nodes::Node {
-        let mut left = self.term();
+    fn arith(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        let mut left = self.term()?;
         loop {
             if let Some(tok) = self.get_cur_tok() {
                 match tok {
                     TT::PLUS | TT::MINUS => {
                         self.advance();
-                        left = nodes::Node::BinOp(Box::new(left), tok, Box::new(self.term()));
+                        let right = self.term()?;
+                        left = self.make(NodeKind::BinOp(Box::new(left), tok, Box::new(right)), start);
                     }
                     _ => break,
                 }
@@ -190,23 +416,25 @@ impl Parser {
                 break;
             }
         }
-        return left;
+        return Ok(left);
     }
 
-    fn expr(&mut self) -> nodes::Node {
+    fn expr(&mut self) -> Result<nodes::Node, ParseError> {
         return self.arith();
     }
 
-    fn pipe(&mut self) -> nodes::Node {
-        let mut left = self.expr();
+    fn pipe(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        let mut left = self.expr()?;
         while let Some(TT::PIPE) = self.get_cur_tok() {
             self.advance();
-            left = nodes::Node::Pipe(Box::new(left), Box::new(self.expr()));
+            let right = self.expr()?;
+            left = self.make(NodeKind::Pipe(Box::new(left), Box::new(right)), start);
         }
-        return left;
+        return Ok(left);
     }
 
-    fn var_assign(&mut self) -> nodes::Node {
+    fn var_assign(&mut self, start: Span) -> Result<nodes::Node, ParseError> {
         let ident = self.get_cur_tok();
         match ident {
             Some(TT::IDENT(name)) => {
@@ -214,182 +442,268 @@ impl Parser {
                 match self.get_cur_tok() {
                     Some(TT::EQ) => {
                         self.advance();
-                        return nodes::Node::VarAssign(name.clone(), Box::new(self.statement()));
+                        let value = self.statement()?;
+                        return Ok(self.make(NodeKind::VarAssign(name.clone(), Box::new(value)), start));
                     }
                     Some(TT::LPAREN) => {
                         self.advance();
-                        let mut params: Vec<String> = vec![];
-                        while let Some(TT::IDENT(x)) = self.get_cur_tok() {
-                            params.push(x);
-                            self.advance();
-                            match self.get_cur_tok() {
-                                Some(TT::COMMA) => {
-                                    self.advance();
-                                    continue;
+                        let params = self.comma_separated(TT::RPAREN, "unclosed parameter list", |p| {
+                            match p.get_cur_tok() {
+                                Some(TT::IDENT(x)) => {
+                                    p.advance();
+                                    Ok(x)
                                 }
-                                Some(TT::RPAREN) => break,
-                                _ => panic!("Expected ')' or ','"),
+                                _ => Err(p.expected("a parameter name or ')'")),
                             }
-                        }
-                        self.advance();
+                        })?;
                         match self.get_cur_tok() {
                             Some(TT::EQ) => {
                                 self.advance();
                             }
-                            _ => panic!("Expected '='"),
+                            _ => return Err(self.expected("'='")
+                                .with_hint("function definitions look like 'var name(params) = ... rav'")),
                         }
                         let stmnt = self.multi_statement();
-                        match self.get_cur_tok() {
-                            Some(TT::KEYWORD(Keyword::Rav)) => {
-                                self.advance();
-                            }
-                            _ => panic!("Expected 'rav'"),
-                        }
-                        return nodes::Node::FuncAssign {
+                        self.expect_keyword(Keyword::Rav, "function bodies are closed with 'rav'")?;
+                        return Ok(self.make(NodeKind::FuncAssign {
                             name,
                             params,
-                            body: Box::new(stmnt),
-                        };
+                            body: Rc::new(stmnt),
+                        }, start));
                     }
-                    _ => panic!("NOt done"),
-                };
+                    _ => Err(self.expected("'=' or '('")
+                        .with_hint("use 'var name = value' or 'var name(params) = ... rav'")),
+                }
             }
-            Some(..) | None => panic!("Expected identifer"),
+            Some(..) | None => Err(self.expected("an identifier after 'var'")),
         }
     }
 
-    fn if_statement(&mut self) -> nodes::Node {
-        let condition = self.statement();
+    fn if_statement(&mut self, start: Span) -> Result<nodes::Node, ParseError> {
+        let condition = self.statement()?;
 
-        if let Some(TT::KEYWORD(Keyword::Then)) = self.get_cur_tok() {
+        self.expect_keyword(Keyword::Then, "an 'if' condition is followed by 'then'")?;
+        let program = self.multi_statement();
+        let mut else_code: Option<Box<nodes::Node>> = None;
+        if let Some(TT::KEYWORD(Keyword::Else)) = self.get_cur_tok() {
             self.advance();
-            let program = self.multi_statement();
-            let mut else_code: Option<Box<nodes::Node>> = None;
-            while let Some(TT::KEYWORD(kwd)) = self.get_cur_tok() {
-                match kwd {
-                    Keyword::Else => {
-                        self.advance();
-                        else_code = Some(Box::new(self.multi_statement()))
-                    }
-                    Keyword::Fi => {
-                        self.advance();
-                        return nodes::Node::If {
-                            condition: Box::new(condition),
-                            body: Box::new(program),
-                            else_body: else_code,
-                        };
-                    }
-                    _ => break,
-                }
-            }
-            panic!("Expected 'then' after condition")
+            else_code = Some(Box::new(self.multi_statement()));
         }
-        panic!("Expected 'then' after condition")
+        self.expect_keyword(Keyword::Fi, "'if' blocks are closed with 'fi'")?;
+        return Ok(self.make(NodeKind::If {
+            condition: Box::new(condition),
+            body: Box::new(program),
+            else_body: else_code,
+        }, start));
     }
 
-    fn while_loop(&mut self) -> nodes::Node {
-        let condition = self.statement();
+    fn while_loop(&mut self, start: Span) -> Result<nodes::Node, ParseError> {
+        let condition = self.statement()?;
 
-        if let Some(TT::KEYWORD(Keyword::Do)) = self.get_cur_tok() {
+        self.expect_keyword(Keyword::Do, "a 'while' condition is followed by 'do'")?;
+        let program = self.multi_statement();
+        self.expect_keyword(Keyword::End, "'while' loops are closed with 'end'")?;
+        return Ok(self.make(NodeKind::While {
+            condition: Box::new(condition),
+            code: Box::new(program),
+        }, start));
+    }
+
+    /// Comparisons can be chained: `a < b <= c` means `a < b and b <= c`, with `b` evaluated once.
+    fn comp(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        let first = self.pipe()?;
+        let mut rest = vec![];
+        while let Some(t @ (TT::LT | TT::GE | TT::GT | TT::LE | TT::EQEQ | TT::NE)) = self.get_cur_tok() {
             self.advance();
-            let program = self.multi_statement();
-            if let Some(TT::KEYWORD(Keyword::End)) = self.get_cur_tok() {
-                self.advance();
-                return nodes::Node::While {
-                    condition: Box::new(condition),
-                    code: Box::new(program),
-                };
-            }
-            panic!("Expected 'end' to end while loop")
+            rest.push((t, self.pipe()?));
+        }
+        if rest.is_empty() {
+            return Ok(first);
+        }
+        if rest.len() == 1 {
+            let (op, right) = rest.remove(0);
+            return Ok(self.make(NodeKind::BinOp(Box::new(first), op, Box::new(right)), start));
         }
-        panic!("Expected 'do' to start while loop")
+        return Ok(self.make(NodeKind::Comparison { first: Box::new(first), rest }, start));
     }
 
-    fn comp(&mut self) -> nodes::Node {
-        let left = self.pipe();
-        let tok = self.get_cur_tok();
-        if let Some(t) = tok {
-            match t {
-                TT::LT | TT::GE | TT::GT | TT::LE | TT::EQEQ => {
-                    self.advance();
-                    nodes::Node::BinOp(Box::new(left), t, Box::new(self.pipe()))
-                }
-                _ => left,
-            }
-        } else {
-            return left;
+    fn not_expr(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        if let Some(TT::KEYWORD(Keyword::Not)) = self.get_cur_tok() {
+            self.advance();
+            let right = self.not_expr()?;
+            return Ok(self.make(NodeKind::UnOp(TT::KEYWORD(Keyword::Not), Box::new(right)), start));
+        }
+        return self.comp();
+    }
+
+    fn and_expr(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        let mut left = self.not_expr()?;
+        while let Some(TT::KEYWORD(Keyword::And)) = self.get_cur_tok() {
+            self.advance();
+            let right = self.not_expr()?;
+            left = self.make(NodeKind::BinOp(Box::new(left), TT::KEYWORD(Keyword::And), Box::new(right)), start);
         }
+        return Ok(left);
     }
 
-    fn struct_create(&mut self) -> nodes::Node {
+    fn or_expr(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
+        let mut left = self.and_expr()?;
+        while let Some(TT::KEYWORD(Keyword::Or)) = self.get_cur_tok() {
+            self.advance();
+            let right = self.and_expr()?;
+            left = self.make(NodeKind::BinOp(Box::new(left), TT::KEYWORD(Keyword::Or), Box::new(right)), start);
+        }
+        return Ok(left);
+    }
+
+    fn struct_create(&mut self, start: Span) -> Result<nodes::Node, ParseError> {
         if let Some(TT::IDENT(struct_name)) = self.get_cur_tok() {
             self.advance();
             if let Some(TT::EQ) = self.get_cur_tok() {
                 let mut names = vec![];
+                let mut defaults = vec![];
+                let mut methods = vec![];
                 self.advance();
-                while let Some(TT::IDENT(name)) = self.get_cur_tok() {
-                    names.push(name);
-                    self.advance();
-                    if let Some(TT::COMMA) = self.get_cur_tok() {
-                        self.advance();
-                        continue;
+                loop {
+                    let item_start = self.cur_span();
+                    match self.get_cur_tok() {
+                        Some(TT::IDENT(name)) => {
+                            self.advance();
+                            if let Some(TT::EQ) = self.get_cur_tok() {
+                                self.advance();
+                                defaults.push((name.clone(), self.statement()?));
+                            }
+                            names.push(name);
+                        }
+                        Some(TT::KEYWORD(Keyword::Var)) => {
+                            self.advance();
+                            let next = self.tokens.get(self.cur_idx + 1).map(|tok| &tok.tt);
+                            if let (Some(TT::IDENT(..)), false) = (self.get_cur_tok(), matches!(next, Some(TT::LPAREN))) {
+                                self.advance();
+                                return Err(self.expected("'('")
+                                    .with_hint("methods look like 'var name(params) = ... rav', fields with a default look like 'name = value'"));
+                            }
+                            methods.push(self.var_assign(item_start)?);
+                        }
+                        _ => break,
+                    }
+                    match self.get_cur_tok() {
+                        Some(TT::COMMA | TT::SEMI) => {
+                            self.advance();
+                        }
+                        _ => break,
                     }
-                    break;
-                }
-                if let Some(TT::KEYWORD(Keyword::End)) = self.get_cur_tok() {
-                    self.advance();
-                    return nodes::Node::StructCreate {
-                        name: struct_name,
-                        fields: names,
-                    };
                 }
-                panic!("Expected 'end' to end struct");
+                self.expect_keyword(Keyword::End, "struct fields and methods are separated by ',' or ';' and closed with 'end'")?;
+                return Ok(self.make(NodeKind::StructCreate {
+                    name: struct_name,
+                    fields: names,
+                    defaults,
+                    methods,
+                }, start));
             } else {
-                panic!("Expected '=' after struct name");
+                return Err(self.expected("'=' after struct name"));
             }
         } else {
-            panic!("Expected identifier after 'struct'");
+            return Err(self.expected("an identifier after 'struct'"));
         }
     }
 
-    fn statement(&mut self) -> nodes::Node {
+    fn statement(&mut self) -> Result<nodes::Node, ParseError> {
+        let start = self.cur_span();
         let res: nodes::Node = match self.get_cur_tok() {
             Some(TT::KEYWORD(Keyword::Var)) => {
                 self.advance();
-                self.var_assign()
+                self.var_assign(start)?
             }
             Some(TT::KEYWORD(Keyword::If)) => {
                 self.advance();
-                self.if_statement()
+                self.if_statement(start)?
             }
             Some(TT::KEYWORD(Keyword::While)) => {
                 self.advance();
-                self.while_loop()
+                self.while_loop(start)?
             }
             Some(TT::KEYWORD(Keyword::Struct)) => {
                 self.advance();
-                self.struct_create()
+                self.struct_create(start)?
             }
-            Some(_t) => self.comp(),
-            None => panic!("Statements must be an expression, var, if, or while"),
+            Some(_t) => self.or_expr()?,
+            None => return Err(self.expected("a statement")
+                .with_hint("statements must be an expression, var, if, while or struct")),
         };
-        return res;
+        return Ok(res);
     }
 
+    /// Parses statements separated by `;`.
+    ///
+    /// Syntax errors are recorded rather than returned: the broken statement becomes a
+    /// `NodeKind::Error` and parsing resumes after the next `;`.
     fn multi_statement(&mut self) -> nodes::Node {
-        let mut nodes: Vec<nodes::Node> = vec![self.statement()];
-        while let Some(TT::SEMI) = self.get_cur_tok() {
-            self.advance();
-            if let None = self.get_cur_tok() {
-                break;
+        let start = self.cur_span();
+        let mut nodes: Vec<nodes::Node> = vec![];
+        loop {
+            let stmnt_start = self.cur_span();
+            match self.statement() {
+                Ok(stmnt) => nodes.push(stmnt),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize();
+                    nodes.push(self.make(NodeKind::Error, stmnt_start));
+                }
+            }
+            match self.get_cur_tok() {
+                Some(TT::SEMI) => {
+                    self.advance();
+                    if self.at_block_end() {
+                        break;
+                    }
+                }
+                _ if self.at_block_end() => break,
+                _ => {
+                    self.errors.push(self.expected("';'")
+                        .with_hint("statements must be separated by ';'"));
+                    self.synchronize();
+                    if let Some(TT::SEMI) = self.get_cur_tok() {
+                        self.advance();
+                    }
+                    if self.at_block_end() {
+                        break;
+                    }
+                }
             }
-            nodes.push(self.statement());
         }
-        return nodes::Node::MultiStatement(nodes);
+        return self.make(NodeKind::MultiStatement(nodes), start);
     }
 
     fn program(&mut self) -> nodes::Node {
-        return nodes::Node::Program(Box::new(self.multi_statement()));
+        let start = self.cur_span();
+        let mut nodes: Vec<nodes::Node> = vec![];
+        loop {
+            if let NodeKind::MultiStatement(mut stmnts) = self.multi_statement().kind {
+                nodes.append(&mut stmnts);
+            }
+            // stray closing tokens at the top level, e.g. an extra 'fi'
+            while self.get_cur_tok().is_some() && self.at_block_end() {
+                if self.errors.last().map(|err| err.span) != Some(self.cur_span()) {
+                    self.errors.push(self.expected("a statement")
+                        .with_hint("this does not close any open block"));
+                }
+                self.advance();
+                if let Some(TT::SEMI) = self.get_cur_tok() {
+                    self.advance();
+                }
+            }
+            if self.get_cur_tok().is_none() {
+                break;
+            }
+        }
+        let program = self.make(NodeKind::MultiStatement(nodes), start);
+        return self.make(NodeKind::Program(Box::new(program)), start);
     }
 
     fn advance(&mut self) -> bool {
@@ -397,3 +711,58 @@ impl Parser {
         return self.cur_idx < self.tokens.len();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse(text: &str) -> Vec<ParseError> {
+        let toks = Lexer::new(text.to_owned()).parse().expect("source should lex");
+        return Parser::new(toks).parse_partial().1;
+    }
+
+    fn lines(errors: &[ParseError]) -> Vec<usize> {
+        return errors.iter().map(|err| err.span.line).collect();
+    }
+
+    #[test]
+    fn valid_program_has_no_errors() {
+        assert!(parse("var a = (1 + 2) * 3;\nvar f(x) = x rav;\nprintln(f(a))").is_empty());
+    }
+
+    #[test]
+    fn reports_every_bad_statement_once() {
+        let errors = parse("var a = ;\nvar b = 1;\nvar c = * 2;\nprintln(b)");
+        assert_eq!(lines(&errors), vec![1, 3]);
+    }
+
+    #[test]
+    fn missing_semicolon_is_one_error() {
+        let errors = parse("var a = 1\nvar b = 2;\nprintln(a + b)");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].expected, "';'");
+        assert_eq!(lines(&errors), vec![2]);
+    }
+
+    #[test]
+    fn recovers_inside_blocks() {
+        let errors = parse("if 1 then var = 2; 3 else 4 fi;\nwhile 0 do * end;\nvar x = ;\nx");
+        assert_eq!(lines(&errors), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn unclosed_paren_is_reported_at_the_paren() {
+        let errors = parse("var a = (1 + 2;\nvar b = 3;\nvar c = ;\nprintln(b)");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message.as_deref(), Some("unclosed '('"));
+        assert_eq!((errors[0].span.line, errors[0].span.col), (1, 9));
+        assert_eq!((errors[1].span.line, errors[1].span.col), (3, 9));
+    }
+
+    #[test]
+    fn stray_closing_keyword() {
+        let errors = parse("var a = 1;\nfi;\na");
+        assert_eq!(lines(&errors), vec![2]);
+    }
+}
@@ -0,0 +1,32 @@
+/// A region of the source text.
+///
+/// `start` and `end` are byte offsets into the source, `line` and `col` are
+/// the 1-based position of `start`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, col: usize) -> Span {
+        return Span {
+            start,
+            end,
+            line,
+            col,
+        };
+    }
+
+    /// Span covering everything from the start of `self` to the end of `other`.
+    pub fn to(&self, other: &Span) -> Span {
+        return Span {
+            start: self.start,
+            end: std::cmp::max(self.end, other.end),
+            line: self.line,
+            col: self.col,
+        };
+    }
+}
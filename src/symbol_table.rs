@@ -1,52 +1,177 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
 
-use crate::types::{self, Type};
+use crate::error::RuntimeError;
+use crate::types::{PathSegment, Type};
 
+/// The bindings of one scope, plus the scope it is nested in.
 #[derive(Debug)]
-pub struct SymbolTable<'a> {
-    pub symbols: &'a mut HashMap<String, Type>,
-    pub parent: Option<Box<&'a SymbolTable<'a>>>
+struct SymbolTable {
+    symbols: HashMap<String, Type>,
+    parent: Option<Env>
 }
 
-pub trait BoxClone {
-    fn clone_box(&self) -> Box<&SymbolTable>;
+/// A scope shared by reference: cloning an `Env` gives another handle to the same bindings.
+///
+/// Every call gets a fresh child scope, and functions and code values keep the `Env` they were
+/// created in and run in a child of it, so the names in their bodies resolve where they were
+/// written rather than where they are called, and outlive the call that created them.
+/// All access goes through the methods below, which never hold a borrow while escr code runs.
+///
+/// A function stored in the scope it was defined in would keep that scope alive through its
+/// closure, so values hold the scope they are stored in weakly and get a strong handle back
+/// when they are read out of it.
+#[derive(Clone)]
+pub struct Env(Link);
+
+#[derive(Clone)]
+enum Link {
+    Strong(Rc<RefCell<SymbolTable>>),
+    Weak(Weak<RefCell<SymbolTable>>),
 }
 
-impl BoxClone for SymbolTable<'_>{
-    fn clone_box(&self) -> Box<&SymbolTable> {
-        Box::new(self.clone())
+impl Env {
+    pub fn new(parent: Option<Env>) -> Env {
+        return Env(Link::Strong(Rc::new(RefCell::new(SymbolTable {
+            symbols: HashMap::new(),
+            parent,
+        }))));
     }
-}
 
+    fn rc(&self) -> Rc<RefCell<SymbolTable>> {
+        match &self.0 {
+            Link::Strong(rc) => rc.clone(),
+            Link::Weak(weak) => weak.upgrade().expect("scope used after it was freed"),
+        }
+    }
 
-impl SymbolTable<'_> {
+    /// A handle that doesn't keep the scope alive.
+    pub fn downgrade(&self) -> Env {
+        return match &self.0 {
+            Link::Strong(rc) => Env(Link::Weak(Rc::downgrade(rc))),
+            Link::Weak(_) => self.clone(),
+        };
+    }
 
-    pub fn var_lookup(&self, dotted_name: &Vec<String>) -> Type {
-        if let Some(item) = self.symbols.get(&dotted_name[0]){
-            let mut current_type = item.to_owned();
-            for i in 1..dotted_name.len(){
-                let temp = current_type.getattr(&dotted_name[i]);
-                if let types::Type::Undefined = temp {
-                    break;
-                }
-                current_type = temp;
-            }
-            return current_type
+    /// A handle that keeps the scope alive. The scope must still exist.
+    fn upgrade(&self) -> Env {
+        return Env(Link::Strong(self.rc()));
+    }
+
+    #[cfg(test)]
+    pub fn is_alive(&self) -> bool {
+        return match &self.0 {
+            Link::Strong(_) => true,
+            Link::Weak(weak) => weak.strong_count() > 0,
+        };
+    }
+
+    /// A new, empty scope nested in this one.
+    pub fn child(&self) -> Env {
+        return Env::new(Some(self.clone()));
+    }
+
+    /// Binds `key` in this scope, shadowing any binding in a parent.
+    pub fn define(&self, key: &str, mut value: Type) {
+        self.hold_weakly(&mut value);
+        self.rc().borrow_mut().symbols.insert(key.to_owned(), value);
+    }
+
+    /// Rebinds `key` in the nearest scope that already has it, returning false if no scope does.
+    pub fn assign(&self, key: &str, mut value: Type) -> bool {
+        let rc = self.rc();
+        let mut table = rc.borrow_mut();
+        if let Some(item) = table.symbols.get_mut(key) {
+            self.hold_weakly(&mut value);
+            *item = value;
+            return true;
+        }
+        match &table.parent {
+            Some(parent) => parent.assign(key, value),
+            None => false,
+        }
+    }
+
+    /// Replaces the part of `key` that `path` leads to, in place in the nearest scope that has
+    /// `key`, so the rest of its value isn't copied. `None` if no scope has `key`.
+    pub fn set_path(&self, key: &str, path: &[PathSegment], mut value: Type) -> Option<Result<(), RuntimeError>> {
+        let rc = self.rc();
+        let mut table = rc.borrow_mut();
+        if let Some(item) = table.symbols.get_mut(key) {
+            self.hold_weakly(&mut value);
+            return Some(item.set_path(path, value));
+        }
+        match &table.parent {
+            Some(parent) => parent.set_path(key, path, value),
+            None => None,
         }
-        Type::Undefined
     }
 
-    pub fn clone_item(&self, key: &String) -> Type {
-        let item = self.symbols.get(key);
-        if let Some(i) = item {
-            let clone = i.clone();
-            return clone;
+    /// Looks `key` up in this scope and then its parents, or `None` if it isn't bound anywhere.
+    pub fn clone_item(&self, key: &str) -> Option<Type> {
+        let rc = self.rc();
+        let table = rc.borrow();
+        if let Some(item) = table.symbols.get(key) {
+            let mut item = item.clone();
+            hold_strongly(&mut item);
+            return Some(item);
         }
-        else if let Some(parent) = &self.parent {
-            return parent.clone_item(key);
+        match &table.parent {
+            Some(parent) => parent.clone_item(key),
+            None => None,
         }
-        else {
-            return Type::Undefined;
+    }
+
+    /// Whether both handles point at the same scope.
+    pub fn same(&self, other: &Env) -> bool {
+        return self.ptr() == other.ptr();
+    }
+
+    fn ptr(&self) -> *const RefCell<SymbolTable> {
+        return match &self.0 {
+            Link::Strong(rc) => Rc::as_ptr(rc),
+            Link::Weak(weak) => weak.as_ptr(),
+        };
+    }
+
+    /// Makes the closures in `value` that point at this scope weak, before storing it here.
+    fn hold_weakly(&self, value: &mut Type) {
+        for_each_closure(value, &mut |closure| {
+            if closure.same(self) {
+                *closure = closure.downgrade();
+            }
+        });
+    }
+}
+
+/// Undoes `Env::hold_weakly` on a value read out of a scope, which is still alive while it is
+/// being read from.
+fn hold_strongly(value: &mut Type) {
+    for_each_closure(value, &mut |closure| {
+        if let Link::Weak(_) = closure.0 {
+            *closure = closure.upgrade();
         }
+    });
+}
+
+/// Calls `f` on the scope of every function and code value in `value`, including those inside
+/// lists, maps, struct instances and struct defaults.
+fn for_each_closure(value: &mut Type, f: &mut impl FnMut(&mut Env)) {
+    match value {
+        Type::Function { closure, .. } | Type::Node { closure, .. } => f(closure),
+        Type::List(items) => items.iter_mut().for_each(|item| for_each_closure(item, f)),
+        Type::Map(entries) => entries.values_mut().for_each(|(_, item)| for_each_closure(item, f)),
+        Type::StructInstance { fields, .. } => fields.values_mut().for_each(|item| for_each_closure(item, f)),
+        Type::Struct { defaults, .. } => defaults.values_mut().for_each(|item| for_each_closure(item, f)),
+        _ => {}
+    }
+}
+
+// scopes can contain functions that point back at them, so don't print the contents
+impl fmt::Debug for Env {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "Env");
     }
 }
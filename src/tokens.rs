@@ -1,3 +1,5 @@
+use crate::span::Span;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Keyword {
     Var,
@@ -11,7 +13,13 @@ pub enum Keyword {
     Edoc,
     Rav,
     Do,
-    Else
+    Else,
+    True,
+    False,
+    And,
+    Or,
+    Not,
+    Nil
 }
 
 impl Keyword{
@@ -28,7 +36,13 @@ impl Keyword{
             Keyword::Then => "then",
             Keyword::Code => "code",
             Keyword::Edoc => "edoc",
-            Keyword::Else => "else"
+            Keyword::Else => "else",
+            Keyword::True => "true",
+            Keyword::False => "false",
+            Keyword::And => "and",
+            Keyword::Or => "or",
+            Keyword::Not => "not",
+            Keyword::Nil => "nil"
         }
     }
 
@@ -46,6 +60,12 @@ impl Keyword{
             "edoc" => Some(Keyword::Edoc),
             "do" => Some(Keyword::Do),
             "while" => Some(Keyword::While),
+            "true" => Some(Keyword::True),
+            "false" => Some(Keyword::False),
+            "and" => Some(Keyword::And),
+            "or" => Some(Keyword::Or),
+            "not" => Some(Keyword::Not),
+            "nil" => Some(Keyword::Nil),
             _ => None
         }
     }
@@ -57,17 +77,24 @@ pub enum TT{
     MINUS,
     MUL,
     DIV,
+    IDIV,
+    MOD,
+    POW,
     LPAREN,
     RPAREN,
     LBRACKET,
     RBRACKET,
+    LBRACE,
+    RBRACE,
     SEMI,
+    COLON,
     EQ,
     GT,
     GE,
     LT,
     LE,
     EQEQ,
+    NE,
     COMMA,
     KEYWORD(Keyword),
     IDENT(String),
@@ -77,6 +104,21 @@ pub enum TT{
     DOT
 }
 
+/// A token together with the part of the source it was read from.
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub tt: TT,
+    pub span: Span,
+}
+
+/// Source text the lexer skips, such as comments, kept for tooling.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct Trivia {
+    pub text: String,
+    pub span: Span,
+}
+
 impl TT{
     pub fn to_string(&self) -> String {
          match self {
@@ -85,17 +127,24 @@ impl TT{
             TT::MINUS => return "-".to_string(),
             TT::MUL => return "*".to_string(),
             TT::DIV => return "/".to_string(),
+            TT::IDIV => return "~/".to_string(),
+            TT::MOD => return "%".to_string(),
+            TT::POW => return "**".to_string(),
             TT::LPAREN => return "(".to_string(),
             TT::RPAREN => return ")".to_string(),
             TT::LBRACKET => return "[".to_string(),
             TT::RBRACKET => return "]".to_string(),
+            TT::LBRACE => return "{".to_string(),
+            TT::RBRACE => return "}".to_string(),
             TT::SEMI => return ";".to_string(),
+            TT::COLON => return ":".to_string(),
             TT::EQ => return "=".to_string(),
             TT::GT => return ">".to_string(),
             TT::LT => return "<".to_string(),
             TT::GE => return ">=".to_string(),
             TT::LE => return "<=".to_string(),
             TT::EQEQ => return "==".to_string(),
+            TT::NE => return "!=".to_string(),
             TT::COMMA => return ",".to_string(),
             TT::KEYWORD(kwd) => return String::from("Keyword(") + kwd.as_str() + ")",
             TT::IDENT(ident) => return String::from("Ident(") + &ident + ")",
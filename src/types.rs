@@ -1,17 +1,44 @@
 use core::fmt;
 
-use std::{collections::HashMap, f64::NAN};
+use std::{cell::Cell, collections::HashMap, f64::NAN, rc::Rc};
+
+use indexmap::IndexMap;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{FromPrimitive, Signed, ToPrimitive, Zero};
 
 use crate::{
+    error::RuntimeError,
     nodes::{self, Node},
-    symbol_table::SymbolTable,
+    symbol_table::Env,
 };
 
+/// One step from a variable to the part of it being assigned to, e.g. `a.b[0]` is the
+/// variable `a` followed by `Field("b")` and `Index(0)`.
+pub enum PathSegment {
+    Field(String),
+    Index(Type),
+}
+
 pub enum Number {
     Int(i64),
+    Big(BigInt),
     Float(f64),
 }
 
+/// The hashable form of a value used as a map key. Numbers that are equal under `==` get
+/// the same key, so `m[1]` and `m[1.0]` are the same entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    Int(BigInt),
+    /// A float that is not a whole number, by its bits.
+    Float(u64),
+    String(String),
+    List(Vec<MapKey>),
+}
+
 pub trait BoxClone {
     fn clone_box(&self) -> Box<Type>;
 }
@@ -21,154 +48,519 @@ pub trait BoxClone {
 pub enum Type {
     Float(f64),
     Int(i64),
+    /// An integer too large for `Int`. Arithmetic switches to this on i64 overflow and
+    /// results that fit back in an i64 are turned back into `Int`.
+    BigInt(BigInt),
+    Bool(bool),
     String(String),
-    Undefined,
+    /// The absence of a value, written `nil`.
+    Nil,
     List(Vec<Box<Type>>),
-    Node(nodes::Node),
+    /// Key-value pairs in insertion order, looked up by `MapKey`. The original key is kept
+    /// next to its value for `keys` and printing.
+    Map(IndexMap<MapKey, (Type, Type)>),
+    /// A `code ... edoc` block and the scope it was written in.
+    Node {
+        code: Rc<nodes::Node>,
+        closure: Env,
+    },
+    /// A struct declaration. `defaults` holds the values of fields that may be left out when
+    /// constructing an instance, and `methods` are functions that get the instance as `self`.
     Struct{
         name: String,
-        fields: Vec<String>
+        fields: Vec<String>,
+        defaults: HashMap<String, Type>,
+        methods: Rc<HashMap<String, Type>>,
     },
     StructInstance {
         name: String,
-        fields: HashMap<String, Type>
+        fields: HashMap<String, Type>,
+        methods: Rc<HashMap<String, Type>>,
     },
+    /// A user defined function, which runs in a child of the scope it was defined in.
     Function {
         name: String,
         parameters: Vec<String>,
-        code: Node,
+        code: Rc<Node>,
+        closure: Env,
     },
     BuiltinFunction {
         name: String,
         parameters: Vec<String>,
         #[derivative(Debug="ignore")]
-        code: fn(env: &mut SymbolTable, params: Vec<Box<Type>>) -> Type
+        code: fn(env: &Env, params: Vec<Box<Type>>) -> Result<Type, RuntimeError>
     },
 }
 
 
+/// Applies `$op` to two numbers. Int op Int is checked and falls back to BigInt on overflow;
+/// if either side is a Float both are promoted to Float.
 macro_rules! bin_op_numbers {
-    ($left:ident $op:tt $right:ident) => {
-        match $left {
-            Number::Int(x) => match $right {
-                Number::Int(y) => Type::Int(x $op y),
-                Number::Float(y) => Type::Int(x $op y as i64),
-            },
-            Number::Float(x) => match $right {
-                Number::Float(y) => Type::Float(x $op y),
-                Number::Int(y) => Type::Float(x $op y as f64),
+    ($left:ident $op:tt $right:ident, $checked:ident) => {
+        match ($left, $right) {
+            (Number::Int(x), Number::Int(y)) => match x.$checked(y) {
+                Some(n) => Ok(Type::Int(n)),
+                None => Ok(Type::from_big(BigInt::from(x) $op BigInt::from(y))),
             },
+            (Number::Float(x), y) => Ok(Type::Float(x $op y.as_f64())),
+            (x, Number::Float(y)) => Ok(Type::Float(x.as_f64() $op y)),
+            (x, y) => Ok(Type::from_big(x.into_big() $op y.into_big())),
         }
     };
 }
 
+impl Number {
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(n) => *n == 0,
+            Number::Big(n) => n.is_zero(),
+            Number::Float(n) => *n == 0.0,
+        }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Number::Int(n) => *n as f64,
+            Number::Big(n) => n.to_f64().unwrap_or(NAN),
+            Number::Float(n) => *n,
+        }
+    }
+
+    /// Integer value as a BigInt; floats are truncated.
+    pub fn into_big(self) -> BigInt {
+        match self {
+            Number::Int(n) => BigInt::from(n),
+            Number::Big(n) => n,
+            Number::Float(n) => BigInt::from(n as i64),
+        }
+    }
+}
+
+/// The longest string, in bytes, that repeating a string with `*` may build.
+const MAX_STRING_LEN: usize = 1 << 30;
+
+fn division_by_zero() -> RuntimeError {
+    return RuntimeError::new("division by zero".to_owned());
+}
+
 impl Type {
     pub fn access(&self) -> Type {
         return self.to_owned();
     }
-    pub fn setattr(&mut self, name: &String, value: Type) -> Type {
+    /// The method `name` of a struct instance, with `self` bound to a copy of the instance in
+    /// the returned scope. Fields take precedence, so a field holding a function is not a method.
+    pub fn bind_method(&self, name: &str) -> Option<(Type, Env)> {
         match self {
-            Type::StructInstance{fields, ..} => {
-                if let Some(..) = fields.get(name){
-                    fields.insert(name.to_owned(), value.to_owned());
-                    return value;
+            Type::StructInstance { fields, methods, .. } if !fields.contains_key(name) => {
+                match methods.get(name) {
+                    Some(Type::Function { name, parameters, code, closure }) => {
+                        let bound = closure.child();
+                        bound.define("self", self.clone());
+                        let method = Type::Function {
+                            name: name.to_owned(),
+                            parameters: parameters.to_owned(),
+                            code: code.clone(),
+                            closure: bound.clone(),
+                        };
+                        Some((method, bound))
+                    }
+                    _ => None,
                 }
-                Type::Undefined
             }
-            _ => Type::Undefined
+            _ => None,
         }
     }
-    pub fn getattr(&self, name: &String) -> Type {
+
+    fn no_field(&self, name: &str) -> RuntimeError {
+        return RuntimeError::new(format!("{} has no field {}", self.type_name(), name));
+    }
+    /// `self[index]`: an element of a list or a one character string, or the value stored
+    /// under a key of a map. Negative indices count from the end.
+    pub fn index(&self, index: &Type) -> Result<Type, RuntimeError> {
         match self {
-            Type::StructInstance{fields, ..} => {
-                if let Some(item) = fields.get(name){
-                    return item.to_owned();
+            Type::Map(entries) => {
+                return match entries.get(&index.map_key()?) {
+                    Some((_, value)) => Ok(value.clone()),
+                    None => Err(RuntimeError::new(format!("key {} not found", index.describe()))),
+                };
+            }
+            Type::List(items) => {
+                let idx = resolve_index(index, items.len())?;
+                return Ok(*items[idx].clone());
+            }
+            Type::String(s) => {
+                let chars: Vec<char> = s.chars().collect();
+                let idx = resolve_index(index, chars.len())?;
+                return Ok(Type::String(chars[idx].to_string()));
+            }
+            _ => Err(RuntimeError::new(format!("Cannot index into {}", self.type_name()))),
+        }
+    }
+
+    /// `self[from:to]`. Missing bounds mean the start and end, and bounds past either end are
+    /// clamped, so slicing never fails on a list or string.
+    pub fn slice(&self, from: Option<&Type>, to: Option<&Type>) -> Result<Type, RuntimeError> {
+        let len = match self {
+            Type::List(items) => items.len(),
+            Type::String(s) => s.chars().count(),
+            _ => return Err(RuntimeError::new(format!("Cannot slice {}", self.type_name()))),
+        };
+        let from = match from {
+            Some(idx) => clamp_index(idx, len)?,
+            None => 0,
+        };
+        let to = match to {
+            Some(idx) => clamp_index(idx, len)?,
+            None => len,
+        };
+        let to = std::cmp::max(from, to);
+        match self {
+            Type::List(items) => Ok(Type::List(items[from..to].to_vec())),
+            Type::String(s) => Ok(Type::String(s.chars().skip(from).take(to - from).collect())),
+            _ => unreachable!(),
+        }
+    }
+
+    /// `self.field[index]...`: the part of the value `path` leads to, read the same way as the
+    /// equivalent field accesses and indexing.
+    pub fn get_path(&self, path: &[PathSegment]) -> Result<Type, RuntimeError> {
+        let mut value = self.clone();
+        for segment in path {
+            value = match segment {
+                PathSegment::Field(name) => value.dot_access(name.to_owned())?,
+                PathSegment::Index(index) => value.index(index)?,
+            };
+        }
+        return Ok(value);
+    }
+
+    /// `self.field[index]... = value`: replaces a field of a struct instance or an element of a
+    /// list, a character of a string or the value under a map key, following `path` through any
+    /// nesting. The last index into a map may be a new key, which is then added; struct instances
+    /// only accept the fields they were declared with.
+    pub fn set_path(&mut self, path: &[PathSegment], value: Type) -> Result<(), RuntimeError> {
+        let (segment, rest) = match path.split_first() {
+            Some(split) => split,
+            None => {
+                *self = value;
+                return Ok(());
+            }
+        };
+        let index = match segment {
+            PathSegment::Field(name) => {
+                return match self {
+                    Type::StructInstance { fields, .. } if fields.contains_key(name) => {
+                        fields.get_mut(name).unwrap().set_path(rest, value)
+                    }
+                    _ => Err(self.no_field(name)),
+                };
+            }
+            PathSegment::Index(index) => index,
+        };
+        match self {
+            Type::List(items) => {
+                let idx = resolve_index(index, items.len())?;
+                return items[idx].set_path(rest, value);
+            }
+            Type::Map(entries) => {
+                let key = index.map_key()?;
+                match entries.get_mut(&key) {
+                    Some((_, old)) => return old.set_path(rest, value),
+                    None if rest.is_empty() => {
+                        entries.insert(key, (index.clone(), value));
+                        return Ok(());
+                    }
+                    None => Err(RuntimeError::new(format!("key {} not found", index.describe()))),
                 }
-                Type::Undefined
             }
-            _ => Type::Undefined
+            Type::String(s) if rest.is_empty() => {
+                let mut chars: Vec<char> = s.chars().collect();
+                let idx = resolve_index(index, chars.len())?;
+                let ch = match &value {
+                    Type::String(v) if v.chars().count() == 1 => v.chars().next().unwrap(),
+                    Type::String(_) => return Err(RuntimeError::new(format!(
+                        "Can only put a single character into a String, not {}", value.describe()
+                    ))),
+                    v => return Err(RuntimeError::new(format!("Cannot put {} into a String", v.type_name()))),
+                };
+                chars[idx] = ch;
+                *s = chars.into_iter().collect();
+                return Ok(());
+            }
+            _ => Err(RuntimeError::new(format!("Cannot assign to an element of {}", self.type_name()))),
+        }
+    }
+
+    /// Both operands as numbers, or an error naming `op` if either isn't numeric.
+    fn numeric_operands(&self, other: &Type, op: &str) -> Result<(Number, Number), RuntimeError> {
+        if self.is_numeric() && other.is_numeric() {
+            return Ok((self.into_number(), other.into_number()));
         }
+        return Err(RuntimeError::new(format!(
+            "Cannot use {} on {} and {}", op, self.type_name(), other.type_name()
+        )));
+    }
+
+    pub fn sub(&self, other: &Type) -> Result<Type, RuntimeError> {
+        let (left, right) = self.numeric_operands(other, "-")?;
+
+        bin_op_numbers!(left - right, checked_sub)
     }
 
-    pub fn sub(&self, other: &Type) -> Type {
-        let left = self.into_number();
-        let right = other.into_number();
+    /// Adds numbers or concatenates strings.
+    pub fn add(&self, other: &Type) -> Result<Type, RuntimeError> {
+        if let (Type::String(l), Type::String(r)) = (self, other) {
+            return Ok(Type::String(l.to_owned() + r));
+        }
+        let (left, right) = self.numeric_operands(other, "+")?;
 
-        bin_op_numbers!(left - right)
+        bin_op_numbers!(left + right, checked_add)
+    }
+
+    /// Multiplies numbers, or repeats a string when the other side is an integer.
+    pub fn mul(&self, other: &Type) -> Result<Type, RuntimeError> {
+        match (self, other) {
+            (Type::String(s), Type::Int(n)) | (Type::Int(n), Type::String(s)) => {
+                let len = usize::try_from(*n).ok().and_then(|n| s.len().checked_mul(n));
+                return match len {
+                    Some(len) if len <= MAX_STRING_LEN => Ok(Type::String(s.repeat(*n as usize))),
+                    _ => Err(RuntimeError::new(format!("Cannot repeat a string {} times", n))),
+                };
+            }
+            _ => {}
+        }
+        let (left, right) = self.numeric_operands(other, "*")?;
+        bin_op_numbers!(left * right, checked_mul)
+    }
+
+    pub fn div(&self, other: &Type) -> Result<Type, RuntimeError> {
+        let (left, right) = self.numeric_operands(other, "/")?;
+        if right.is_zero() {
+            return Err(division_by_zero());
+        }
+        bin_op_numbers!(left / right, checked_div)
+    }
+
+    /// Integer division rounding towards negative infinity, so `-7 ~/ 2` is `-4`.
+    pub fn idiv(&self, other: &Type) -> Result<Type, RuntimeError> {
+        let (left, right) = self.numeric_operands(other, "~/")?;
+        if right.is_zero() {
+            return Err(division_by_zero());
+        }
+        match (left, right) {
+            (Number::Int(x), Number::Int(y)) if x.checked_div(y).is_some() => Ok(Type::Int(Integer::div_floor(&x, &y))),
+            (x @ Number::Float(_), y) | (x, y @ Number::Float(_)) => Ok(Type::Float((x.as_f64() / y.as_f64()).floor())),
+            (x, y) => Ok(Type::from_big(x.into_big().div_floor(&y.into_big()))),
+        }
     }
-    pub fn add(&self, other: &Type) -> Type {
-        let left = self.into_number();
-        let right = other.into_number();
 
-        bin_op_numbers!(left + right)
+    /// Remainder that takes the sign of the divisor, matching `idiv`.
+    pub fn rem(&self, other: &Type) -> Result<Type, RuntimeError> {
+        let (left, right) = self.numeric_operands(other, "%")?;
+        if right.is_zero() {
+            return Err(division_by_zero());
+        }
+        match (left, right) {
+            (Number::Int(x), Number::Int(y)) if x.checked_rem(y).is_some() => Ok(Type::Int(Integer::mod_floor(&x, &y))),
+            (x @ Number::Float(_), y) | (x, y @ Number::Float(_)) => {
+                let (x, y) = (x.as_f64(), y.as_f64());
+                let r = x % y;
+                if r != 0.0 && (r < 0.0) != (y < 0.0) {
+                    return Ok(Type::Float(r + y));
+                }
+                Ok(Type::Float(r))
+            }
+            (x, y) => Ok(Type::from_big(x.into_big().mod_floor(&y.into_big()))),
+        }
     }
 
-    pub fn mul(&self, other: &Type) -> Type {
-        let left = self.into_number();
-        let right = other.into_number();
-        bin_op_numbers!(left * right)
+    /// Integer powers stay integers unless the exponent is negative.
+    pub fn pow(&self, other: &Type) -> Result<Type, RuntimeError> {
+        match self.numeric_operands(other, "**")? {
+            (x @ (Number::Int(_) | Number::Big(_)), Number::Int(y)) if y >= 0 => {
+                let exp = match u32::try_from(y) {
+                    Ok(exp) => exp,
+                    Err(_) => return Err(RuntimeError::new(format!("exponent {} is too large", y))),
+                };
+                if let Number::Int(x) = x {
+                    if let Some(n) = x.checked_pow(exp) {
+                        return Ok(Type::Int(n));
+                    }
+                }
+                Ok(Type::from_big(x.into_big().pow(exp)))
+            }
+            (Number::Int(_) | Number::Big(_), Number::Big(y)) if y.is_positive() => Err(RuntimeError::new(format!("exponent {} is too large", y))),
+            (x, y) => Ok(Type::Float(x.as_f64().powf(y.as_f64()))),
+        }
     }
 
-    pub fn div(&self, other: &Type) -> Type {
-        let left = self.into_number();
-        let right = other.into_number();
-        bin_op_numbers!(left / right)
+    /// Wraps an integer result, using `Int` whenever it fits.
+    pub fn from_big(n: BigInt) -> Type {
+        match n.to_i64() {
+            Some(n) => Type::Int(n),
+            None => Type::BigInt(n),
+        }
     }
 
-    pub fn dot_access(&self, name: String) -> Type {
+    pub fn dot_access(&self, name: String) -> Result<Type, RuntimeError> {
+        if let Some((method, _)) = self.bind_method(&name) {
+            return Ok(method);
+        }
         match self.access() {
             Type::StructInstance{fields, ..} => {
                 if let Some(val) = fields.get(&name.to_owned()){
-                    return val.to_owned();
+                    return Ok(val.to_owned());
                 }
-                return Type::Undefined
+                Err(self.no_field(&name))
             }
-            Type::String(x) => {
-                if name == "length" {
-                    return Type::Int(x.len() as i64);
-                }
-                Type::Undefined
+            Type::String(x) if name == "length" => Ok(Type::Int(x.chars().count() as i64)),
+            Type::List(x) if name == "length" => Ok(Type::Int(x.len() as i64)),
+            Type::Map(x) if name == "length" => Ok(Type::Int(x.len() as i64)),
+            _ => Err(self.no_field(&name))
+        }
+    }
+
+    /// Values that take part in arithmetic. Booleans are not numbers, so `true + 1` is an error.
+    pub fn is_numeric(&self) -> bool {
+        return matches!(self, Type::Int(_) | Type::BigInt(_) | Type::Float(_));
+    }
+
+    /// The key this value is stored under in a map. Only nil, booleans, numbers other than
+    /// NaN, strings and lists of those can be keys.
+    pub fn map_key(&self) -> Result<MapKey, RuntimeError> {
+        let key = match self {
+            Type::Nil => MapKey::Nil,
+            Type::Bool(b) => MapKey::Bool(*b),
+            Type::Int(n) => MapKey::Int(BigInt::from(*n)),
+            Type::BigInt(n) => MapKey::Int(n.clone()),
+            Type::Float(n) if n.is_nan() => return Err(RuntimeError::new("NaN cannot be used as a map key".to_owned())),
+            Type::Float(n) if n.fract() == 0.0 => MapKey::Int(BigInt::from_f64(*n).unwrap()),
+            Type::Float(n) => MapKey::Float(n.to_bits()),
+            Type::String(s) => MapKey::String(s.clone()),
+            Type::List(items) => MapKey::List(items.iter().map(|item| item.map_key()).collect::<Result<_, _>>()?),
+            _ => return Err(RuntimeError::new(format!("{} cannot be used as a map key", self.type_name()))),
+        };
+        return Ok(key);
+    }
+
+    /// Value equality used by `==` and `!=`. Numbers compare by value across Int and Float,
+    /// strings by content, and lists and struct instances element by element. Functions and
+    /// code blocks are only equal to themselves, i.e. values coming from the same definition.
+    /// Values of different kinds are never equal.
+    pub fn equals(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::String(l), Type::String(r)) => l == r,
+            (Type::Bool(l), Type::Bool(r)) => l == r,
+            (l, r) if l.is_numeric() && r.is_numeric() => l.compare_numbers(r) == Some(std::cmp::Ordering::Equal),
+            (Type::List(l), Type::List(r)) => l.len() == r.len() && l.iter().zip(r).all(|(x, y)| x.equals(y)),
+            (Type::Map(l), Type::Map(r)) => {
+                l.len() == r.len() && r.iter().all(|(k, (_, v))| l.get(k).map_or(false, |(_, w)| w.equals(v)))
             }
-            Type::List(x) => {
-                if name == "length" {
-                    return Type::Int(x.len() as i64)
-                }
-                Type::Undefined
+            (Type::StructInstance { name: l_name, fields: l, .. }, Type::StructInstance { name: r_name, fields: r, .. }) => {
+                l_name == r_name && l.len() == r.len() && l.iter().all(|(k, v)| r.get(k).map_or(false, |w| v.equals(w)))
+            }
+            (Type::Struct { name: l_name, fields: l, methods: l_methods, .. }, Type::Struct { name: r_name, fields: r, methods: r_methods, .. }) => {
+                l_name == r_name && l == r && Rc::ptr_eq(l_methods, r_methods)
+            }
+            (
+                Type::Function { name: l_name, code: l, closure: l_env, .. },
+                Type::Function { name: r_name, code: r, closure: r_env, .. },
+            ) => l_name == r_name && Rc::ptr_eq(l, r) && l_env.same(r_env),
+            (Type::BuiltinFunction { name: l, .. }, Type::BuiltinFunction { name: r, .. }) => l == r,
+            (Type::Node { code: l, closure: l_env }, Type::Node { code: r, closure: r_env }) => {
+                Rc::ptr_eq(l, r) && l_env.same(r_env)
             }
-            _ => Type::Undefined
+            (Type::Nil, Type::Nil) => true,
+            _ => false,
+        }
+    }
+
+    /// Ordering used by `<`, `<=`, `>` and `>=`: numbers by value, strings lexicographically.
+    pub fn compare(&self, other: &Type) -> Result<Option<std::cmp::Ordering>, RuntimeError> {
+        match (self, other) {
+            (Type::String(l), Type::String(r)) => Ok(Some(l.cmp(r))),
+            (l, r) if l.is_numeric() && r.is_numeric() => Ok(l.compare_numbers(r)),
+            (l, r) => Err(RuntimeError::new(format!("Cannot compare {} and {}", l.type_name(), r.type_name()))),
+        }
+    }
+
+    /// Compares two numeric values, promoting to Float if either side is one.
+    fn compare_numbers(&self, other: &Type) -> Option<std::cmp::Ordering> {
+        match (self.into_number(), other.into_number()) {
+            (Number::Int(l), Number::Int(r)) => Some(l.cmp(&r)),
+            (l @ Number::Float(_), r) | (l, r @ Number::Float(_)) => l.as_f64().partial_cmp(&r.as_f64()),
+            (l, r) => Some(l.into_big().cmp(&r.into_big())),
+        }
+    }
+
+    pub fn type_name(&self) -> &str {
+        match self {
+            Type::Float(_) => "Float",
+            Type::Int(_) | Type::BigInt(_) => "Int",
+            Type::Bool(_) => "Bool",
+            Type::String(_) => "String",
+            Type::Nil => "Nil",
+            Type::List(_) => "List",
+            Type::Map(_) => "Map",
+            Type::Node { .. } => "Code",
+            Type::Struct { .. } => "Struct",
+            Type::StructInstance { name, .. } => name,
+            Type::Function { .. } | Type::BuiltinFunction { .. } => "Function",
+        }
+    }
+
+    /// Whether the value counts as true in `if`, `while`, `and`, `or` and `not`.
+    ///
+    /// `false`, `nil`, zero, NaN and empty strings, lists and maps are false; everything else,
+    /// including functions, code values and structs, is true.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Type::Bool(b) => *b,
+            Type::Nil => false,
+            Type::Int(n) => *n != 0,
+            Type::BigInt(n) => !n.is_zero(),
+            Type::Float(n) => *n != 0.0 && !n.is_nan(),
+            Type::String(s) => !s.is_empty(),
+            Type::List(items) => !items.is_empty(),
+            Type::Map(entries) => !entries.is_empty(),
+            Type::Function { .. } | Type::BuiltinFunction { .. } | Type::Node { .. } => true,
+            Type::Struct { .. } | Type::StructInstance { .. } => true,
         }
     }
 
     pub fn into_number(&self) -> Number {
         match self.access() {
             Type::Int(n) => Number::Int(n.to_owned()),
+            Type::BigInt(n) => Number::Big(n),
+            Type::Bool(b) => Number::Int(b as i64),
             Type::Float(n) => Number::Float(n.to_owned()),
-            Type::String(x) => Number::Int(x.len() as i64),
-            Type::Function { .. } | Type::BuiltinFunction { .. } | Type::Node(..) => Number::Int(0),
-            Type::Undefined => Number::Float(NAN),
+            Type::String(x) => Number::Int(x.chars().count() as i64),
+            Type::Function { .. } | Type::BuiltinFunction { .. } | Type::Node { .. } => Number::Int(0),
+            Type::Nil => Number::Int(0),
             Type::List(n) => Number::Int(n.len() as i64),
+            Type::Map(n) => Number::Int(n.len() as i64),
             Type::Struct { .. } => Number::Int(0),
-            Type::StructInstance{fields, ..} => {
-                if let Some(Type::Function{name: _name, parameters: _params, code: _code}) = fields.get("into_number") {
-                    Number::Int(1)
-                }
-                else {
-                    Number::Int(0)
-                }
-            }
+            Type::StructInstance { .. } => Number::Int(1),
         }
     }
 
-    pub fn into_repr(&self) -> String {
-        match self {
+    /// How the value is shown inside lists and by `repr`. Struct instances with a `repr` method
+    /// are shown as whatever it returns.
+    pub fn into_repr(&self) -> Result<String, RuntimeError> {
+        let text = match self {
             Type::Int(x) => x.to_string(),
+            Type::BigInt(x) => x.to_string(),
+            Type::Bool(x) => x.to_string(),
             Type::Float(x) => x.to_string(),
-            Type::List(..) => self.into_string(),
-            Type::Undefined => "[[undefined]]".to_string(),
-            Type::Node(n) => n.repr(0),
+            Type::List(..) | Type::Map(..) => self.into_string()?,
+            Type::Nil => "nil".to_string(),
+            Type::Node { code, .. } => code.repr(0),
             Type::BuiltinFunction { .. } => "[[builtin function]]".to_string(),
             Type::Struct { name, .. } => "[[".to_owned() + name + "]]",
-            Type::StructInstance { name, .. } => "[[".to_owned() + name + "()]]",
+            Type::StructInstance { name, .. } => match self.call_string_method("repr") {
+                Some(text) => text?,
+                None => "[[".to_owned() + name + "()]]",
+            },
             Type::Function {
                 name, parameters, ..
             } => {
@@ -181,98 +573,300 @@ impl Type {
                     }
                 }
                 text += &String::from(")");
-                return text;
+                text
             }
             Type::String(s) => "\"".to_owned() + s + "\"",
-        }
+        };
+        return Ok(text);
     }
 
-    pub fn into_string(&self) -> String {
-        match self.access() {
+    /// How the value is printed. Struct instances with a `to_string` method are printed as
+    /// whatever it returns.
+    pub fn into_string(&self) -> Result<String, RuntimeError> {
+        let text = match self.access() {
             Type::Int(x) => x.to_string(),
+            Type::BigInt(x) => x.to_string(),
+            Type::Bool(x) => x.to_string(),
             Type::Float(x) => x.to_string(),
             Type::String(x) => x,
             Type::Function { .. } => "[[function]]".to_string(),
             Type::BuiltinFunction { .. } => "[[builtin function]]".to_string(),
-            Type::Undefined => "undefined".to_string(),
-            Type::Node(..) => "[[node]]".to_owned(),
+            Type::Nil => "nil".to_string(),
+            Type::Node { .. } => "[[node]]".to_owned(),
             Type::Struct { name, .. } => name.to_owned(),
-            Type::StructInstance { name, .. } => name + "()",
+            Type::StructInstance { name, .. } => match self.call_string_method("to_string") {
+                Some(text) => text?,
+                None => name + "()",
+            },
             Type::List(n) => {
                 let mut text = String::from("[");
                 for i in 0..n.len() {
-                    text += &n[i].into_repr();
+                    text += &n[i].into_repr()?;
                     if i < n.len() - 1 {
                         text += &String::from(", ");
                     }
                 }
                 text += "]";
-                return text;
+                text
             }
-        }
-    }
-
-    pub fn run(&self, given_params: Vec<Type>, env: &mut SymbolTable) -> Type {
-        match self {
-            Type::Node(n) => n.visit(env),
-            Type::Struct {name, fields} => {
-                let mut map = HashMap::new();
-                let mut i = 0;
-                for field in fields {
-                    if i >= given_params.len() {
-                        break;
-                    }
-                    map.insert(field.to_owned(), given_params[i].clone());
-                    i += 1;
+            Type::Map(entries) => {
+                let mut items = vec![];
+                for (key, value) in entries.values() {
+                    items.push(key.into_repr()? + ": " + &value.into_repr()?);
                 }
-                return Type::StructInstance{name: name.to_owned(), fields: map}
+                "{".to_owned() + &items.join(", ") + "}"
             }
-            Type::BuiltinFunction { name, parameters, code } => {
-                let mut symbols: HashMap<String, Type> = HashMap::new();
-                let mut args = vec![];
-                //we pick the smallest so that if they dont provide exactly the right amount of args, it
-                //doesn't error.
-                for i in 0..std::cmp::min(given_params.len(), parameters.len()) {
-                    symbols.insert(parameters[i].clone(), given_params[i].clone());
-                    args.push(Box::new(given_params[i].clone()));
-                }
-                for i in std::cmp::min(given_params.len(), parameters.len())..given_params.len() {
-                    args.push(Box::new(given_params[i].clone()));
-                }
+        };
+        return Ok(text);
+    }
 
-                symbols.insert("ARGV".to_owned(), Type::List(args.clone()));
-                let mut child_table = SymbolTable {
-                    parent: Some(Box::new(env)),
-                    symbols: &mut symbols,
-                };
-                let data = code(&mut child_table, args);
+    /// `into_repr` for error messages, falling back to the type name if a `repr` method fails.
+    pub fn describe(&self) -> String {
+        return self.into_repr().unwrap_or_else(|_| self.type_name().to_owned());
+    }
+
+    /// Calls the method `name` on a struct instance, or `None` if it doesn't have one.
+    pub fn call_method(&self, name: &str, args: Vec<Type>) -> Option<Result<Type, RuntimeError>> {
+        let (method, bound) = self.bind_method(name)?;
+        return Some(method.run(args, vec![], &bound));
+    }
+
+    /// Calls a method that has to return a string, such as `to_string`.
+    fn call_string_method(&self, name: &str) -> Option<Result<String, RuntimeError>> {
+        let res = match self.call_method(name, vec![])? {
+            Ok(Type::String(text)) => Ok(text),
+            Ok(other) => Err(RuntimeError::new(format!("{} must return a String, not {}", name, other.type_name()))),
+            Err(err) => Err(err),
+        };
+        return Some(res);
+    }
+
+    /// Calls a function or code value, or constructs an instance of a struct. Only structs take
+    /// `named_params`, matched to fields by name.
+    pub fn run(&self, given_params: Vec<Type>, named_params: Vec<(String, Type)>, env: &Env) -> Result<Type, RuntimeError> {
+        if !named_params.is_empty() && !matches!(self, Type::Struct { .. }) {
+            return Err(RuntimeError::new(format!("{} does not take named arguments", self.type_name())));
+        }
+        match self {
+            Type::Node { code, closure } => nested_call(|| code.visit(&closure.child())),
+            Type::Struct { name, fields, defaults, methods } => construct(name, fields, defaults, methods, given_params, named_params),
+            Type::BuiltinFunction { name: _name, parameters, code } => {
+                let child = env.child();
+                let args = bind_arguments(&child, parameters, given_params);
+                let data = code(&child, args);
                 return data;
             }
             Type::Function {
                 name: _name,
                 parameters,
                 code,
+                closure,
             } => {
-                let mut symbols: HashMap<String, Type> = HashMap::new();
-                let mut args = vec![];
-                //we pick the smallest so that if they dont provide exactly the right amount of args, it
-                //doesn't error.
-                for i in 0..std::cmp::min(given_params.len(), parameters.len()) {
-                    symbols.insert(parameters[i].clone(), given_params[i].clone());
-                    args.push(Box::new(given_params[i].clone()));
+                let child = closure.child();
+                bind_arguments(&child, parameters, given_params);
+                return nested_call(|| code.visit(&child));
+            }
+            _ => Err(RuntimeError::new(format!("Cannot call {}", self.describe()))),
+        }
+    }
+}
+
+/// How deep calls to escr functions and code values may nest before it is treated as runaway
+/// recursion. The interpreter thread's stack is sized to fit this many.
+pub const MAX_CALL_DEPTH: usize = 2000;
+
+thread_local! {
+    /// The number of escr functions and code values currently running.
+    static CALL_DEPTH: Cell<usize> = Cell::new(0);
+}
+
+/// Runs the body of a call one level deeper, or fails once `MAX_CALL_DEPTH` is reached
+/// rather than letting the native stack overflow.
+fn nested_call(body: impl FnOnce() -> Result<Type, RuntimeError>) -> Result<Type, RuntimeError> {
+    let depth = CALL_DEPTH.with(|d| d.get());
+    if depth >= MAX_CALL_DEPTH {
+        return Err(RuntimeError::new("maximum recursion depth exceeded".to_owned()));
+    }
+    CALL_DEPTH.with(|d| d.set(depth + 1));
+    let res = body();
+    CALL_DEPTH.with(|d| d.set(depth));
+    return res;
+}
+
+/// Builds an instance of the struct `name` from positional and named field values, filling in
+/// defaults for the fields that were left out.
+#[inline(never)]
+fn construct(
+    name: &str,
+    fields: &[String],
+    defaults: &HashMap<String, Type>,
+    methods: &Rc<HashMap<String, Type>>,
+    given_params: Vec<Type>,
+    named_params: Vec<(String, Type)>,
+) -> Result<Type, RuntimeError> {
+    if given_params.len() > fields.len() {
+        return Err(RuntimeError::new(format!(
+            "{} has {} field(s) but {} were given", name, fields.len(), given_params.len()
+        )));
+    }
+    let mut map: HashMap<String, Type> = fields.iter().cloned().zip(given_params).collect();
+    for (field, value) in named_params {
+        if !fields.contains(&field) {
+            return Err(RuntimeError::new(format!("{} has no field {}", name, field)));
+        }
+        if map.insert(field.to_owned(), value).is_some() {
+            return Err(RuntimeError::new(format!("field {} of {} was given more than once", field, name)));
+        }
+    }
+    let mut missing = vec![];
+    for field in fields {
+        if !map.contains_key(field) {
+            match defaults.get(field) {
+                Some(default) => {
+                    map.insert(field.to_owned(), default.clone());
                 }
-                for i in std::cmp::min(given_params.len(), parameters.len())..given_params.len() {
-                    args.push(Box::new(given_params[i].clone()));
+                None => missing.push(field.as_str()),
+            }
+        }
+    }
+    if !missing.is_empty() {
+        return Err(RuntimeError::new(format!("missing field(s) {} for {}", missing.join(", "), name)));
+    }
+    return Ok(Type::StructInstance{name: name.to_owned(), fields: map, methods: methods.clone()});
+}
+
+/// Binds each parameter to the argument in the same position and all arguments to `ARGV`.
+/// Missing arguments are left unbound and extra ones only appear in `ARGV`.
+fn bind_arguments(env: &Env, parameters: &[String], given_params: Vec<Type>) -> Vec<Box<Type>> {
+    for (param, arg) in parameters.iter().zip(&given_params) {
+        env.define(param, arg.clone());
+    }
+    let args: Vec<Box<Type>> = given_params.into_iter().map(Box::new).collect();
+    env.define("ARGV", Type::List(args.clone()));
+    return args;
+}
+
+/// Turns an escr index into a position in a sequence of length `len`, counting negative
+/// indices from the end.
+fn resolve_index(index: &Type, len: usize) -> Result<usize, RuntimeError> {
+    let idx = match index {
+        Type::Int(i) => *i,
+        Type::BigInt(..) => return Err(RuntimeError::new(format!("index {} out of range for length {}", index.describe(), len))),
+        t => return Err(RuntimeError::new(format!("Indices must be Int, not {}", t.type_name()))),
+    };
+    let pos = if idx < 0 { idx + len as i64 } else { idx };
+    if pos < 0 || pos >= len as i64 {
+        return Err(RuntimeError::new(format!("index {} out of range for length {}", idx, len)));
+    }
+    return Ok(pos as usize);
+}
+
+/// Like `resolve_index`, but for slice bounds, which are clamped to `0..=len` instead of failing.
+fn clamp_index(index: &Type, len: usize) -> Result<usize, RuntimeError> {
+    let idx = match index {
+        Type::Int(i) => *i,
+        Type::BigInt(b) if b.is_negative() => i64::MIN,
+        Type::BigInt(..) => i64::MAX,
+        t => return Err(RuntimeError::new(format!("Indices must be Int, not {}", t.type_name()))),
+    };
+    let pos = if idx < 0 { idx.saturating_add(len as i64) } else { idx };
+    return Ok(pos.clamp(0, len as i64) as usize);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn float(res: Result<Type, RuntimeError>) -> f64 {
+        match res {
+            Ok(Type::Float(x)) => x,
+            other => panic!("expected a Float, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mixed_int_and_float_promote_to_float() {
+        assert_eq!(float(Type::Int(1).add(&Type::Float(0.5))), 1.5);
+        assert_eq!(float(Type::Float(0.5).add(&Type::Int(1))), 1.5);
+        assert_eq!(float(Type::Int(3).mul(&Type::Float(0.5))), 1.5);
+        assert_eq!(float(Type::Int(1).sub(&Type::Float(1.5))), -0.5);
+        assert_eq!(float(Type::Int(7).div(&Type::Float(2.0))), 3.5);
+        assert_eq!(float(Type::Float(-7.0).idiv(&Type::Int(2))), -4.0);
+        assert_eq!(Type::Int(1).compare(&Type::Float(1.5)).unwrap(), Some(std::cmp::Ordering::Less));
+        assert_eq!(Type::Float(1.5).compare(&Type::Int(1)).unwrap(), Some(std::cmp::Ordering::Greater));
+    }
+
+    #[test]
+    fn int_arithmetic_stays_int() {
+        assert!(matches!(Type::Int(7).div(&Type::Int(2)), Ok(Type::Int(3))));
+        assert!(matches!(Type::Int(-7).idiv(&Type::Int(2)), Ok(Type::Int(-4))));
+        assert!(matches!(Type::Int(-7).rem(&Type::Int(2)), Ok(Type::Int(1))));
+        assert!(matches!(Type::Int(2).pow(&Type::Int(10)), Ok(Type::Int(1024))));
+    }
+
+    #[test]
+    fn string_repetition() {
+        assert!(matches!(Type::String("ab".to_owned()).mul(&Type::Int(3)), Ok(Type::String(s)) if s == "ababab"));
+        assert!(matches!(Type::Int(0).mul(&Type::String("ab".to_owned())), Ok(Type::String(s)) if s.is_empty()));
+        assert!(Type::String("ab".to_owned()).mul(&Type::Int(-1)).is_err());
+        assert!(Type::String("ab".to_owned()).mul(&Type::Int(i64::MAX)).is_err());
+        assert!(Type::String("ab".to_owned()).mul(&Type::Int(1 << 30)).is_err());
+    }
+
+    #[test]
+    fn map_keys() {
+        let mut map = Type::Map(IndexMap::new());
+        map.set_path(&[PathSegment::Index(Type::Int(1))], Type::String("one".to_owned())).unwrap();
+        map.set_path(&[PathSegment::Index(Type::Float(1.0))], Type::String("uno".to_owned())).unwrap();
+        assert!(matches!(map.index(&Type::Int(1)), Ok(Type::String(s)) if s == "uno"));
+        assert!(matches!(&map, Type::Map(entries) if entries.len() == 1));
+        assert!(map.set_path(&[PathSegment::Index(Type::Float(f64::NAN))], Type::Nil).is_err());
+        let list = Type::List(vec![Box::new(Type::Map(IndexMap::new()))]);
+        assert!(map.set_path(&[PathSegment::Index(list)], Type::Nil).is_err());
+    }
+
+    #[test]
+    fn float_to_a_huge_power() {
+        let huge = Type::from_big(BigInt::from(10).pow(20));
+        assert_eq!(float(Type::Float(2.0).pow(&huge)), f64::INFINITY);
+        assert_eq!(float(Type::Float(0.5).pow(&huge)), 0.0);
+        assert!(Type::Int(2).pow(&huge).is_err());
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        for zero in [Type::Int(0), Type::Float(0.0)] {
+            for res in [Type::Int(1).div(&zero), Type::Int(1).idiv(&zero), Type::Float(1.0).rem(&zero)] {
+                match res {
+                    Err(err) => assert_eq!(err.message, "division by zero"),
+                    Ok(val) => panic!("expected an error, got {:?}", val),
                 }
-                symbols.insert("ARGV".to_owned(), Type::List(args));
-                let mut child_table = SymbolTable {
-                    parent: Some(Box::new(env)),
-                    symbols: &mut symbols,
-                };
-                let data = code.visit(&mut child_table);
-                return data;
             }
-            _ => panic!("Cannot call {:?}", self),
         }
     }
+
+    #[test]
+    fn booleans_are_not_numbers() {
+        assert!(!Type::Bool(true).equals(&Type::Int(1)));
+        assert!(!Type::Int(0).equals(&Type::Bool(false)));
+        assert!(Type::Bool(true).equals(&Type::Bool(true)));
+        assert!(Type::Bool(true).add(&Type::Int(1)).is_err());
+        assert!(Type::Int(1).compare(&Type::Bool(false)).is_err());
+    }
+
+    #[test]
+    fn i64_overflow_promotes_to_big_int_and_back() {
+        let big = Type::Int(i64::MAX).add(&Type::Int(1)).unwrap();
+        match &big {
+            Type::BigInt(n) => assert_eq!(*n, BigInt::from(i64::MAX) + 1),
+            other => panic!("expected a BigInt, got {:?}", other),
+        }
+        assert!(matches!(big.sub(&Type::Int(1)), Ok(Type::Int(i64::MAX))));
+        assert!(matches!(Type::Int(i64::MIN).mul(&Type::Int(-1)), Ok(Type::BigInt(_))));
+        assert!(matches!(Type::Int(i64::MIN).div(&Type::Int(-1)), Ok(Type::BigInt(_))));
+        assert!(matches!(Type::Int(i64::MIN).idiv(&Type::Int(-1)), Ok(Type::BigInt(_))));
+        assert!(matches!(Type::Int(i64::MIN).rem(&Type::Int(-1)), Ok(Type::Int(0))));
+        let square = Type::Int(1 << 40).pow(&Type::Int(2)).unwrap();
+        assert!(matches!(square.div(&Type::Int(1 << 40)), Ok(Type::Int(n)) if n == 1 << 40));
+    }
 }